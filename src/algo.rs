@@ -0,0 +1,294 @@
+//! The hash algorithms `--algo` can select, behind a common `Digest` trait so
+//! that the reading code (mmap, sparse and buffered) doesn't care which one
+//! it's feeding.
+
+use rayon::prelude::*;
+use std::io::{self, Read, Write};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algo {
+    Blake3,
+    Sha256,
+    Sha512,
+    // Only for compatibility with legacy manifests. SHA-1 is broken.
+    Sha1,
+    Blake2b,
+    // Not cryptographic, but very fast, for catching accidental corruption.
+    Xxh3,
+}
+
+impl Algo {
+    pub const NAMES: [&'static str; 6] = ["blake3", "sha256", "sha512", "sha1", "blake2b", "xxh3"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "blake3" => Self::Blake3,
+            "sha256" => Self::Sha256,
+            "sha512" => Self::Sha512,
+            "sha1" => Self::Sha1,
+            "blake2b" => Self::Blake2b,
+            "xxh3" => Self::Xxh3,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Sha1 => "sha1",
+            Self::Blake2b => "blake2b",
+            Self::Xxh3 => "xxh3",
+        }
+    }
+
+    // The name BSD-style `--tag` lines start with, as the coreutils tools
+    // write it.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Blake3 => "BLAKE3",
+            Self::Sha256 => "SHA256",
+            Self::Sha512 => "SHA512",
+            Self::Sha1 => "SHA1",
+            Self::Blake2b => "BLAKE2b",
+            Self::Xxh3 => "XXH3",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .filter_map(|name| Self::from_name(name))
+            .find(|algo| algo.tag() == tag)
+    }
+
+    // The length of the digest in bytes. BLAKE3 can produce any length, and
+    // this is only its default.
+    pub fn output_len(self) -> usize {
+        match self {
+            Self::Blake3 => blake3::OUT_LEN,
+            Self::Sha256 => 32,
+            Self::Sha512 | Self::Blake2b => 64,
+            Self::Sha1 => 20,
+            Self::Xxh3 => 8,
+        }
+    }
+
+    // A fresh hasher. Keyed and derive-key BLAKE3 hashers are built by the
+    // caller instead.
+    pub fn hasher(self) -> Box<dyn Digest> {
+        match self {
+            Self::Blake3 => Box::new(blake3::Hasher::new()),
+            Self::Sha256 => Box::new(RustCrypto(sha2::Sha256::default())),
+            Self::Sha512 => Box::new(RustCrypto(sha2::Sha512::default())),
+            Self::Sha1 => Box::new(RustCrypto(sha1::Sha1::default())),
+            Self::Blake2b => Box::new(RustCrypto(blake2::Blake2b512::default())),
+            Self::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+        }
+    }
+}
+
+pub trait Digest: Send + Sync {
+    fn update(&mut self, data: &[u8]);
+
+    // Hashes a large buffer, on several threads if the algorithm allows it.
+    // Only BLAKE3 does; the others are inherently serial.
+    fn update_parallel(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+
+    fn finalize(&self) -> HashOutput;
+
+    fn box_clone(&self) -> Box<dyn Digest>;
+}
+
+impl Clone for Box<dyn Digest> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// A finished digest. BLAKE3 is an extendable-output function, so its output is
+// kept as a reader, which --length and --raw can take as much from as they
+// like.
+pub enum HashOutput {
+    Blake3(blake3::OutputReader),
+    Bytes(Vec<u8>),
+    // One digest per algorithm, from `Several`, each at its default length.
+    Several(Vec<Vec<u8>>),
+}
+
+impl HashOutput {
+    // Callers make sure `len` is the algorithm's output length for anything
+    // other than BLAKE3.
+    pub fn bytes(self, len: usize) -> Vec<u8> {
+        match self {
+            Self::Blake3(mut reader) => {
+                let mut bytes = vec![0; len];
+                reader.fill(&mut bytes);
+                bytes
+            }
+            Self::Bytes(bytes) => bytes,
+            Self::Several(digests) => digests.concat(),
+        }
+    }
+
+    // Several digests are joined with commas, in --algo order.
+    pub fn hex(self, len: usize) -> String {
+        match self {
+            Self::Several(digests) => digests
+                .iter()
+                .map(hex::encode)
+                .collect::<Vec<_>>()
+                .join(","),
+            output => hex::encode(output.bytes(len)),
+        }
+    }
+
+    pub fn write_raw(self, len: u64, mut w: impl Write) -> io::Result<()> {
+        match self {
+            Self::Blake3(reader) => io::copy(&mut reader.take(len), &mut w).map(drop),
+            output => w.write_all(&output.bytes(len as usize)),
+        }
+    }
+}
+
+impl Digest for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn update_parallel(&mut self, data: &[u8]) {
+        self.update_rayon(data);
+    }
+
+    fn finalize(&self) -> HashOutput {
+        HashOutput::Blake3(self.finalize_xof())
+    }
+
+    fn box_clone(&self) -> Box<dyn Digest> {
+        Box::new(self.clone())
+    }
+}
+
+// SHA-1, SHA-2 and BLAKE2 all come from RustCrypto, with the same interface.
+#[derive(Clone)]
+struct RustCrypto<D>(D);
+
+impl<D> Digest for RustCrypto<D>
+where
+    D: sha2::Digest + Clone + Send + Sync + 'static,
+{
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data);
+    }
+
+    fn finalize(&self) -> HashOutput {
+        HashOutput::Bytes(self.0.clone().finalize().to_vec())
+    }
+
+    fn box_clone(&self) -> Box<dyn Digest> {
+        Box::new(self.clone())
+    }
+}
+
+// XXH3's 64-bit digest is written big-endian, as xxhsum prints it.
+impl Digest for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finalize(&self) -> HashOutput {
+        HashOutput::Bytes(self.digest().to_be_bytes().to_vec())
+    }
+
+    fn box_clone(&self) -> Box<dyn Digest> {
+        Box::new(self.clone())
+    }
+}
+
+// Feeds every buffer to several hashers, so that `--algo blake3,sha256` reads
+// each file once. The hashers run side by side on the thread pool, which
+// matters most for the serial algorithms: a SHA-256 pass on its own core
+// costs little next to a second read of the file.
+#[derive(Clone)]
+pub struct Several(Vec<(Algo, Box<dyn Digest>)>);
+
+impl Several {
+    pub fn new(algos: &[Algo]) -> Self {
+        Self(algos.iter().map(|&algo| (algo, algo.hasher())).collect())
+    }
+}
+
+impl Digest for Several {
+    fn update(&mut self, data: &[u8]) {
+        self.0
+            .par_iter_mut()
+            .for_each(|(_, hasher)| hasher.update(data));
+    }
+
+    fn update_parallel(&mut self, data: &[u8]) {
+        self.0
+            .par_iter_mut()
+            .for_each(|(_, hasher)| hasher.update_parallel(data));
+    }
+
+    fn finalize(&self) -> HashOutput {
+        HashOutput::Several(
+            self.0
+                .iter()
+                .map(|(algo, hasher)| hasher.finalize().bytes(algo.output_len()))
+                .collect(),
+        )
+    }
+
+    fn box_clone(&self) -> Box<dyn Digest> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLAKE3_ABC: &str = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn hash_abc(hasher: &mut dyn Digest, len: usize) -> String {
+        hasher.update(b"abc");
+        hasher.finalize().hex(len)
+    }
+
+    #[test]
+    fn hashes_known_answers() {
+        let cases = [
+            (Algo::Blake3, BLAKE3_ABC),
+            (Algo::Sha256, SHA256_ABC),
+            (
+                Algo::Sha512,
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            ),
+            (Algo::Sha1, "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                Algo::Blake2b,
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+                 7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923",
+            ),
+            // Big-endian, as xxhsum -H3 prints it.
+            (Algo::Xxh3, "78af5f94892f3950"),
+        ];
+        for (algo, expected) in cases {
+            let hex = hash_abc(&mut *algo.hasher(), algo.output_len());
+            assert_eq!(hex, expected, "{}", algo.name());
+        }
+    }
+
+    #[test]
+    fn joins_several_digests_with_commas() {
+        let mut several = Several::new(&[Algo::Blake3, Algo::Sha256]);
+        let hex = hash_abc(&mut several, 0);
+        assert_eq!(hex, format!("{},{}", BLAKE3_ABC, SHA256_ABC));
+    }
+}
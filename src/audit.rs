@@ -0,0 +1,127 @@
+//! `--audit`: compares by contents as well as by path, like hashdeep's audit
+//! mode, so that a renamed or copied file shows up as MOVED or COPIED rather
+//! than as a MISSING and NEW pair.
+
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+use crate::format::Report;
+use crate::{is_hash_value, report_diff};
+
+// Returns true if anything differs, moves and copies included. A path that's
+// only in the new manifest is MOVED if its hash belongs to a path that's only
+// in the old one, and COPIED if its hash is anywhere else in the old one.
+// Several moves with the same contents are paired up in path order.
+pub fn audit_manifests(
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+    report: &mut Report,
+) -> Result<bool> {
+    let mut old_paths: Vec<&String> = old.keys().collect();
+    old_paths.sort();
+    let mut new_paths: Vec<&String> = new.keys().filter(|p| !old.contains_key(*p)).collect();
+    new_paths.sort();
+
+    // Paths that are gone, and the first path with each hash, by hash.
+    let mut gone: HashMap<&str, Vec<&String>> = HashMap::new();
+    let mut first: HashMap<&str, &String> = HashMap::new();
+    for &path in old_paths.iter().rev() {
+        let value = old[path].as_str();
+        if !is_hash_value(value) {
+            continue;
+        }
+        first.insert(value, path);
+        if !new.contains_key(path) {
+            gone.entry(value).or_default().push(path);
+        }
+    }
+
+    let mut sources: HashMap<&String, (&'static str, &String)> = HashMap::new();
+    let mut moved_away: HashSet<&String> = HashSet::new();
+    for &path in &new_paths {
+        let value = new[path].as_str();
+        if !is_hash_value(value) {
+            continue;
+        }
+        // `gone` was filled in reverse, so popping takes them in path order.
+        if let Some(from) = gone.get_mut(value).and_then(Vec::pop) {
+            sources.insert(path, ("moved", from));
+            moved_away.insert(from);
+        } else if let Some(&from) = first.get(value) {
+            sources.insert(path, ("copied", from));
+        }
+    }
+
+    let mut paths = old_paths;
+    paths.extend(new_paths);
+    paths.sort();
+    let mut differs = false;
+    for path in paths {
+        if moved_away.contains(path) {
+            continue;
+        }
+        if let Some(&(status, from)) = sources.get(path) {
+            report.record_from(path, status, from, &new[path])?;
+            if report.is_text() {
+                println!("{}: {} from {}", path, status.to_uppercase(), from);
+            }
+            differs = true;
+            continue;
+        }
+        differs |= report_diff(
+            report,
+            path,
+            old.get(path).map(String::as_str),
+            new.get(path).map(String::as_str),
+        )?;
+    }
+    Ok(differs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Format;
+
+    fn manifest(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(path, hash)| (path.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_moved_and_copied_files() {
+        let [h1, h2, h3] = ["11".repeat(32), "22".repeat(32), "33".repeat(32)];
+        let old = manifest(&[("a", &h1), ("b", &h2), ("c", &h3)]);
+        // a was renamed to d, and b was copied to e.
+        let new = manifest(&[("b", &h2), ("c", &h3), ("d", &h1), ("e", &h2)]);
+        // JUnit reports keep their records and print nothing until finish().
+        let mut report = Report::start(Format::Junit).unwrap();
+        assert!(audit_manifests(&old, &new, &mut report).unwrap());
+        let records: Vec<(&str, &str, Option<&str>)> = report
+            .records()
+            .iter()
+            .map(|r| (r.key.as_str(), r.status, r.from.as_deref()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("b", "ok", None),
+                ("c", "ok", None),
+                ("d", "moved", Some("a")),
+                ("e", "copied", Some("b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unmatched_files_are_missing_and_new() {
+        let old = manifest(&[("a", &"11".repeat(32)), ("b", "UNSTABLE")]);
+        let new = manifest(&[("c", &"22".repeat(32)), ("d", "UNSTABLE")]);
+        let mut report = Report::start(Format::Junit).unwrap();
+        assert!(audit_manifests(&old, &new, &mut report).unwrap());
+        let statuses: Vec<&str> = report.records().iter().map(|r| r.status).collect();
+        assert_eq!(statuses, ["missing", "missing", "new", "new"]);
+    }
+}
@@ -0,0 +1,472 @@
+//! `dirhash bag create|validate`: BagIt packages (RFC 8493). A bag is a
+//! directory with the payload under `data/`, and next to it `bagit.txt`,
+//! `bag-info.txt`, a `manifest-<alg>.txt` for each algorithm, and
+//! `tagmanifest-<alg>.txt` files covering the other tag files.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::algo::Algo;
+use crate::format::Report;
+use crate::html::format_time;
+use crate::walk::{self, Walked};
+use crate::{
+    diff_manifests, escape_filepath, hash_inputs, hash_one_input, interrupted, report_diff, Args,
+    Entry, Input, Status, NAME,
+};
+
+const BAGIT_TXT: &str = "bagit.txt";
+const BAG_INFO_TXT: &str = "bag-info.txt";
+const DATA_DIR: &str = "data";
+
+fn manifest_name(algo: Algo) -> String {
+    format!("manifest-{}.txt", algo.name())
+}
+
+fn tagmanifest_name(algo: Algo) -> String {
+    format!("tagmanifest-{}.txt", algo.name())
+}
+
+// The algorithms of the bag's payload manifests, for validate without
+// --algo. Manifests for algorithms we don't have (md5, usually) are skipped
+// with a warning.
+pub fn manifest_algos(dir: &Path) -> Result<Vec<Algo>> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    let mut algos = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(algo_name) = name
+            .strip_prefix("manifest-")
+            .and_then(|rest| rest.strip_suffix(".txt"))
+        {
+            match Algo::from_name(algo_name) {
+                Some(algo) => algos.push(algo),
+                None => eprintln!("{}: skipping {}, {} isn't supported", NAME, name, algo_name),
+            }
+        }
+    }
+    ensure!(
+        !algos.is_empty(),
+        "{} has no payload manifest to check",
+        dir.display()
+    );
+    algos.sort_by_key(|algo| Algo::NAMES.iter().position(|name| *name == algo.name()));
+    Ok(algos)
+}
+
+// CR, LF and % are the only characters manifests percent-encode.
+fn encode(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn decode(path: &str) -> String {
+    let mut decoded = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(i) = rest.find('%') {
+        decoded.push_str(&rest[..i]);
+        let escape = rest.get(i + 1..i + 3).unwrap_or("");
+        match escape.to_ascii_uppercase().as_str() {
+            "25" => decoded.push('%'),
+            "0D" => decoded.push('\r'),
+            "0A" => decoded.push('\n'),
+            _ => {
+                decoded.push('%');
+                rest = &rest[i + 1..];
+                continue;
+            }
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Reads `hash path` lines. Paths that would reach outside the bag are
+// refused, since validate opens them.
+fn read_manifest(dir: &Path, name: &str) -> Result<Vec<(String, String)>> {
+    let path = dir.join(name);
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut entries = Vec::new();
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (hash, file) = line
+            .split_once(|c: char| c.is_ascii_whitespace())
+            .with_context(|| format!("Invalid line in {}: {}", name, line))?;
+        let file = decode(file.trim_start());
+        let safe = Path::new(&file)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        ensure!(safe, "{} lists a path outside the bag: {}", name, file);
+        entries.push((file, hash.to_ascii_lowercase()));
+    }
+    Ok(entries)
+}
+
+fn read_labels(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut labels: Vec<(String, String)> = Vec::new();
+    for line in contents.lines() {
+        // Indented lines continue the previous value.
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = labels.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((label, value)) = line.split_once(':') {
+            labels.push((label.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(labels)
+}
+
+fn entry_len(path: &Path, entry: &Entry) -> u64 {
+    match &entry.stamp {
+        Some(stamp) => stamp.len,
+        None => fs::metadata(path).map_or(0, |metadata| metadata.len()),
+    }
+}
+
+// Moves everything in `dir` into `dir/data`, by way of a temporary directory
+// in case the payload already has a top-level `data`. If a move fails, the
+// ones already made are undone, so the directory is left as it was.
+fn move_into_data(dir: &Path) -> Result<()> {
+    let temp = dir.join(".dirhash-bag.tmp");
+    fs::create_dir(&temp).with_context(|| format!("Failed to create {}", temp.display()))?;
+    let mut moved = Vec::new();
+    let result = (|| -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path() == temp {
+                continue;
+            }
+            let to = temp.join(entry.file_name());
+            fs::rename(entry.path(), &to)
+                .with_context(|| format!("Failed to move {}", entry.path().display()))?;
+            moved.push(entry.file_name());
+        }
+        fs::rename(&temp, dir.join(DATA_DIR))?;
+        Ok(())
+    })();
+    if result.is_err() {
+        for name in &moved {
+            if let Err(e) = fs::rename(temp.join(name), dir.join(name)) {
+                eprintln!("{}: failed to move {:?} back: {}", NAME, name, e);
+            }
+        }
+        let _ = fs::remove_dir(&temp);
+    }
+    result
+}
+
+// Writes one manifest per algorithm from `(path, hashes)` pairs, where the
+// hashes are joined with commas in --algo order.
+fn write_manifests(
+    dir: &Path,
+    name: impl Fn(Algo) -> String,
+    algos: &[Algo],
+    entries: &[(String, String)],
+) -> Result<()> {
+    for (i, &algo) in algos.iter().enumerate() {
+        let mut contents = String::new();
+        for (path, hashes) in entries {
+            let hash = hashes.split(',').nth(i).unwrap_or_default();
+            contents += &format!("{}  {}\n", hash, encode(path));
+        }
+        let path = dir.join(name(algo));
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// Turns a directory into a bag in place. Returns true if the run was
+// interrupted.
+pub fn create(args: &'static Args) -> Result<bool> {
+    let dir = args.bag_path();
+    ensure!(dir.is_dir(), "{} isn't a directory", dir.display());
+    ensure!(
+        !dir.join(BAGIT_TXT).exists(),
+        "{} is already a bag",
+        dir.display()
+    );
+    let mut info = Vec::new();
+    for label in args.bag_info() {
+        let (label, value) = label
+            .split_once(':')
+            .with_context(|| format!("--info {:?} should look like `Label: value`", label))?;
+        ensure!(!label.trim().is_empty(), "--info needs a label");
+        info.push(format!("{}: {}", label.trim(), value.trim()));
+    }
+
+    // The payload is hashed where it is, under the names it'll have once
+    // it's in data/, so that a failed or interrupted run leaves nothing to
+    // undo.
+    let mut payload = Vec::new();
+    let mut octets = 0;
+    let errors = hash_inputs(args, None, |path, _, entry| {
+        octets += entry_len(path, &entry);
+        if let Status::Hashed(hashes) = entry.status {
+            let relative = walk::relative_slash_path(dir, path)?;
+            payload.push((format!("{}/{}", DATA_DIR, relative), hashes));
+        }
+        Ok(())
+    })?;
+    if interrupted() {
+        return Ok(true);
+    }
+    if !errors.is_empty() {
+        errors.report();
+        bail!(
+            "Not every payload file could be hashed, so {} wasn't turned into a bag",
+            dir.display()
+        );
+    }
+    payload.sort();
+    move_into_data(dir)?;
+    write_manifests(dir, manifest_name, &args.algos, &payload)?;
+
+    fs::write(
+        dir.join(BAGIT_TXT),
+        "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n",
+    )?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut bag_info = format!(
+        "Bag-Software-Agent: {} {}\nBagging-Date: {}\nPayload-Oxum: {}.{}\n",
+        NAME,
+        env!("CARGO_PKG_VERSION"),
+        &format_time(now)[..10],
+        octets,
+        payload.len()
+    );
+    for line in info {
+        bag_info += &line;
+        bag_info.push('\n');
+    }
+    fs::write(dir.join(BAG_INFO_TXT), bag_info)?;
+
+    // Every tag file other than the tag manifests themselves.
+    let mut tag_files = vec![BAGIT_TXT.to_string(), BAG_INFO_TXT.to_string()];
+    tag_files.extend(args.algos.iter().map(|&algo| manifest_name(algo)));
+    let mut tags = Vec::new();
+    for name in tag_files {
+        let path = dir.join(&name);
+        match hash_one_input(&path, args).status {
+            Status::Hashed(hashes) => tags.push((name, hashes)),
+            status => bail!("Failed to hash {}: {}", path.display(), status),
+        }
+    }
+    write_manifests(dir, tagmanifest_name, &args.algos, &tags)?;
+    Ok(false)
+}
+
+// Problems with the bag as a whole, rather than with a file in it.
+fn check_bagit_txt(dir: &Path, problems: &mut Vec<String>) -> Result<()> {
+    let path = dir.join(BAGIT_TXT);
+    if !path.is_file() {
+        problems.push(format!("{} is missing", BAGIT_TXT));
+        return Ok(());
+    }
+    let labels: HashMap<String, String> = read_labels(&path)?.into_iter().collect();
+    match labels.get("BagIt-Version") {
+        Some(version) if version.starts_with("0.") || version.starts_with("1.") => {}
+        Some(version) => problems.push(format!("unsupported BagIt-Version {}", version)),
+        None => problems.push(format!("{} has no BagIt-Version", BAGIT_TXT)),
+    }
+    match labels.get("Tag-File-Character-Encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("UTF-8") => {}
+        Some(encoding) => problems.push(format!("unsupported tag file encoding {}", encoding)),
+        None => problems.push(format!("{} has no Tag-File-Character-Encoding", BAGIT_TXT)),
+    }
+    Ok(())
+}
+
+// Validates a bag: that it's complete (every payload file is in every
+// manifest, every listed file exists, and Payload-Oxum adds up) and, unless
+// --fast, that every payload and tag file matches its hashes. Returns true if
+// the bag isn't valid.
+pub fn validate(args: &'static Args) -> Result<bool> {
+    let dir = args.bag_path();
+    let mut report = Report::start(args.format())?;
+    let mut problems = Vec::new();
+    check_bagit_txt(dir, &mut problems)?;
+    if !dir.join(DATA_DIR).is_dir() {
+        problems.push(format!("{}/ is missing", DATA_DIR));
+    }
+
+    // The expected hashes of each payload file, joined in --algo order, so
+    // they compare directly against what hash_inputs finds.
+    let mut listed: BTreeMap<String, Vec<Option<String>>> = BTreeMap::new();
+    for (i, &algo) in args.algos.iter().enumerate() {
+        let name = manifest_name(algo);
+        for (path, hash) in read_manifest(dir, &name)? {
+            if !path.starts_with("data/") {
+                problems.push(format!("{} lists {}, outside data/", name, path));
+                continue;
+            }
+            listed
+                .entry(path)
+                .or_insert_with(|| vec![None; args.algos.len()])[i] = Some(hash);
+        }
+    }
+    let mut expected = HashMap::new();
+    for (path, hashes) in listed {
+        let mut joined = Vec::new();
+        for (algo, hash) in args.algos.iter().zip(hashes) {
+            match hash {
+                Some(hash) => joined.push(hash),
+                None => problems.push(format!("{} isn't in {}", path, manifest_name(*algo))),
+            }
+        }
+        expected.insert(escape_filepath(path), joined.join(","));
+    }
+
+    // What's actually there. With --fast nothing is hashed, and files that
+    // are listed stand in with their expected value.
+    let mut found = HashMap::new();
+    let mut octets = 0;
+    let mut streams = 0;
+    if args.fast() {
+        for walked in walk::walk_inputs(args, None)? {
+            let path = match walked? {
+                Walked::File(path) => path,
+                Walked::Other(_) => continue,
+                Walked::Error(e) => bail!(e),
+            };
+            octets += fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            streams += 1;
+            let key = escape_filepath(walk::relative_slash_path(dir, &path)?);
+            let value = expected.get(&key).cloned().unwrap_or_default();
+            found.insert(key, value);
+        }
+    } else {
+        let errors = hash_inputs(args, None, |path, _, entry| {
+            octets += entry_len(path, &entry);
+            streams += 1;
+            found.insert(
+                escape_filepath(walk::relative_slash_path(dir, path)?),
+                entry.status.to_string(),
+            );
+            Ok(())
+        })?;
+        if interrupted() {
+            report.finish(true)?;
+            return Ok(true);
+        }
+        errors.report();
+    }
+    let mut invalid = diff_manifests(&expected, &found, &mut report)?;
+
+    let bag_info = dir.join(BAG_INFO_TXT);
+    if bag_info.is_file() {
+        let oxum = read_labels(&bag_info)?
+            .into_iter()
+            .find(|(label, _)| label == "Payload-Oxum")
+            .map(|(_, value)| value);
+        let actual = format!("{}.{}", octets, streams);
+        if let Some(oxum) = oxum.filter(|oxum| *oxum != actual) {
+            problems.push(format!(
+                "Payload-Oxum is {}, but the payload is {}",
+                oxum, actual
+            ));
+        }
+    }
+
+    if !args.fast() {
+        invalid |= check_tag_manifests(dir, args, &mut report)?;
+    }
+    for problem in &problems {
+        eprintln!("{}: {}: {}", NAME, dir.display(), problem);
+    }
+    invalid |= !problems.is_empty();
+    let text = report.is_text();
+    report.finish(false)?;
+    if text {
+        let verdict = if invalid { "INVALID" } else { "VALID" };
+        println!("{}: {}", dir.display(), verdict);
+    }
+    Ok(invalid)
+}
+
+// Tag manifests are optional, but whatever they list has to match.
+fn check_tag_manifests(dir: &Path, args: &'static Args, report: &mut Report) -> Result<bool> {
+    let mut listed: BTreeMap<String, Vec<(Algo, String)>> = BTreeMap::new();
+    for &algo in &args.algos {
+        let name = tagmanifest_name(algo);
+        if !dir.join(&name).is_file() {
+            continue;
+        }
+        for (path, hash) in read_manifest(dir, &name)? {
+            listed.entry(path).or_default().push((algo, hash));
+        }
+    }
+    let mut invalid = false;
+    for (path, hashes) in listed {
+        let key = escape_filepath(path.clone());
+        let full: PathBuf = dir.join(&path);
+        let expected: Vec<&str> = hashes.iter().map(|(_, hash)| hash.as_str()).collect();
+        let expected = expected.join(",");
+        if !full.is_file() {
+            invalid |= report_diff(report, &key, Some(&expected), None)?;
+            continue;
+        }
+        let mut found = Vec::new();
+        for (algo, _) in &hashes {
+            let output = Input::open(&full, args)
+                .and_then(|mut input| input.hash_with(args, algo.hasher()))
+                .with_context(|| format!("Failed to hash {}", full.display()))?;
+            found.push(output.hex(algo.output_len()));
+        }
+        invalid |= report_diff(report, &key, Some(&expected), Some(&found.join(",")))?;
+    }
+    Ok(invalid)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::{OsStr, OsString};
+    use std::os::unix::ffi::OsStrExt;
+
+    fn listing(dir: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = walkdir::WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn failed_create_leaves_directory_alone() {
+        let dir = std::env::temp_dir().join(format!("dirhash-bag-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        // Manifests are UTF-8, so this name fails the run once it's hashed.
+        fs::write(dir.join(OsStr::from_bytes(b"bad\xff")), "c").unwrap();
+        let before = listing(&dir);
+
+        let argv = ["dirhash", "bag", "create"].map(OsString::from);
+        let argv = argv.into_iter().chain([dir.clone().into_os_string()]);
+        let args: &'static Args = Box::leak(Box::new(Args::parse_from(argv).unwrap()));
+        assert!(create(args).is_err());
+        assert_eq!(listing(&dir), before);
+        assert!(!dir.join(DATA_DIR).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
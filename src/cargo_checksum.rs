@@ -0,0 +1,120 @@
+//! `dirhash cargo-checksum DIR`: the `.cargo-checksum.json` that `cargo vendor`
+//! leaves in each vendored crate. It maps every file in the crate, by its
+//! path relative to the crate with forward slashes, to its SHA-256, and
+//! records the checksum of the `.crate` file the crate came from:
+//!
+//! ```text
+//! {"files":{"Cargo.toml":"5e88...","src/lib.rs":"0cc1..."},"package":"a3f1..."}
+//! ```
+//!
+//! Cargo refuses to build a vendored crate whose files don't match, so after
+//! patching one on purpose, `--update` rewrites the file list. The package
+//! checksum is kept, since it's what Cargo.lock has to agree with, and it
+//! can't be recomputed without the original `.crate` anyway.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::format::Report;
+use crate::output::Output;
+use crate::walk;
+use crate::{diff_manifests, escape_filepath, hash_inputs, interrupted, Args, NAME};
+
+const CHECKSUM_JSON: &str = ".cargo-checksum.json";
+
+// Files cargo vendor doesn't list: the checksum file itself, and the marker
+// Cargo leaves in crates it unpacks.
+fn is_unlisted(path: &str) -> bool {
+    path == CHECKSUM_JSON || path == ".cargo-ok"
+}
+
+// The `files` map, and `package`, which is null for crates that didn't come
+// from a registry.
+fn read(path: &Path) -> Result<(Map<String, Value>, Value)> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut json: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let files = match json.get_mut("files").map(Value::take) {
+        Some(Value::Object(files)) => files,
+        _ => bail!("{} has no \"files\" object", path.display()),
+    };
+    let package = json.get_mut("package").map_or(Value::Null, Value::take);
+    Ok((files, package))
+}
+
+// Checks the crate against its .cargo-checksum.json, or with --update,
+// rewrites the file list from what's on disk. Returns true if anything
+// differs, if a file couldn't be hashed, or if the run was interrupted.
+pub fn main(args: &'static Args) -> Result<bool> {
+    let dir = args.crate_path();
+    let checksum_path = dir.join(CHECKSUM_JSON);
+    let (listed, package) = if args.update() && !checksum_path.exists() {
+        (Map::new(), Value::Null)
+    } else {
+        read(&checksum_path)?
+    };
+
+    let mut found = BTreeMap::new();
+    let errors = hash_inputs(args, None, |path, _, entry| {
+        let relative = walk::relative_slash_path(dir, path)?;
+        if !is_unlisted(&relative) {
+            found.insert(relative, entry.status);
+        }
+        Ok(())
+    })?;
+    if interrupted() {
+        return Ok(true);
+    }
+
+    if args.update() {
+        if !errors.is_empty() {
+            errors.report();
+            bail!(
+                "Not every file could be hashed, so {} wasn't updated",
+                CHECKSUM_JSON
+            );
+        }
+        let files: Map<String, Value> = found
+            .into_iter()
+            .map(|(path, status)| (path, Value::String(status.to_string())))
+            .collect();
+        let count = files.len();
+        // Cargo writes it compact, with the keys in order.
+        let json = json!({ "files": files, "package": package });
+        // Written to a temporary file and renamed into place, so a failed
+        // write can't leave cargo a truncated file.
+        let mut output = Output::open(Some(&checksum_path))?;
+        write!(output, "{}", json)
+            .with_context(|| format!("Failed to write {}", checksum_path.display()))?;
+        output.finish(false)?;
+        eprintln!(
+            "{}: wrote {} with {} files",
+            NAME,
+            checksum_path.display(),
+            count
+        );
+        return Ok(false);
+    }
+
+    let mut expected = HashMap::new();
+    for (path, hash) in listed {
+        let hash = hash
+            .as_str()
+            .with_context(|| format!("{} has a non-string hash for {}", CHECKSUM_JSON, path))?;
+        expected.insert(escape_filepath(path), hash.to_ascii_lowercase());
+    }
+    let found: HashMap<String, String> = found
+        .into_iter()
+        .map(|(path, status)| (escape_filepath(path), status.to_string()))
+        .collect();
+    let mut report = Report::start(args.format())?;
+    let differs = diff_manifests(&expected, &found, &mut report)?;
+    report.finish(false)?;
+    errors.report();
+    Ok(differs || !errors.is_empty())
+}
@@ -0,0 +1,330 @@
+//! Checkpoints that let `hash --resume` pick up an interrupted run without
+//! rehashing everything it already got through.
+
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    filepath_to_string, parse_manifest_line, unescape, write_manifest_line, Args, Entry, FileStamp,
+    Status, NAME,
+};
+
+// v2 adds a `# settings` line after the header, since hashes from a run with
+// other settings are no use to this one. v1 checkpoints didn't record any, so
+// they're only trusted by a run with all the defaults.
+const HEADER: &str = "# dirhash checkpoint v2";
+const HEADER_V1: &str = "# dirhash checkpoint v1";
+const V1_SETTINGS: &str = "# settings algo=blake3 length=32 key=none format=text";
+
+// Everything that changes the hashes or the manifest. Keys and derive-key
+// contexts are recorded by the hash of an empty input, which tells them apart
+// without giving them away.
+fn settings(args: &Args) -> Result<String> {
+    let names: Vec<&str> = args.algos.iter().map(|algo| algo.name()).collect();
+    let key = if args.keyed() || args.derive_key() {
+        let empty = args.base_hasher.finalize().bytes(args.algo().output_len());
+        let kind = if args.keyed() { "keyed" } else { "derive-key" };
+        format!("{}:{}", kind, hex::encode(&empty[..8]))
+    } else {
+        "none".to_string()
+    };
+    Ok(format!(
+        "# settings algo={} length={} key={} format={}",
+        names.join(","),
+        args.len()?,
+        key,
+        args.format().name()
+    ))
+}
+
+pub fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".checkpoint");
+    name.into()
+}
+
+// Stamps are written as seven space-separated fields: length, mtime seconds
+// and nanoseconds (or `- -` if the platform has no mtime), ctime seconds and
+// nanoseconds, device and inode.
+fn write_stamp(mut w: impl Write, stamp: &FileStamp) -> io::Result<()> {
+    write!(w, "{} ", stamp.len)?;
+    match stamp
+        .modified
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+    {
+        Some(since) => write!(w, "{} {} ", since.as_secs(), since.subsec_nanos())?,
+        None => write!(w, "- - ")?,
+    }
+    let (ctime, ctime_nsec) = stamp.changed;
+    let (dev, ino) = stamp.inode;
+    write!(w, "{} {} {} {}", ctime, ctime_nsec, dev, ino)
+}
+
+fn parse_stamp(s: &str) -> Option<FileStamp> {
+    let fields: Vec<&str> = s.split(' ').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+    let modified = match (fields[1], fields[2]) {
+        ("-", "-") => None,
+        (secs, nanos) => {
+            Some(SystemTime::UNIX_EPOCH + Duration::new(secs.parse().ok()?, nanos.parse().ok()?))
+        }
+    };
+    Some(FileStamp {
+        len: fields[0].parse().ok()?,
+        modified,
+        changed: (fields[3].parse().ok()?, fields[4].parse().ok()?),
+        inode: (fields[5].parse().ok()?, fields[6].parse().ok()?),
+    })
+}
+
+// The checkpoint is a journal of `stamp<TAB>manifest line`, one per hashed
+// file, appended as the run goes. Lines are buffered and synced every
+// --checkpoint-interval, so a crash loses at most that much work.
+//
+// With --sort, files that couldn't be hashed get a `-<TAB>manifest line`, so
+// that a resumed run knows to try them again, and every sync also writes
+// `# done<TAB>key`: every file up to that one in walk order is above it in the
+// journal, so --resume can skip that part of the tree without walking it.
+const DONE: &str = "# done\t";
+
+pub struct Checkpoint {
+    file: BufWriter<File>,
+    path: PathBuf,
+    interval: Duration,
+    last_sync: Instant,
+    sorted: bool,
+    last: Option<String>,
+}
+
+impl Checkpoint {
+    // With `resume`, entries are appended to the existing checkpoint, so that
+    // a run interrupted twice still remembers what the first one hashed.
+    pub fn create(output: &Path, args: &Args) -> Result<Self> {
+        let resume = args.resume();
+        let interval = args.checkpoint_interval()?;
+        let path = checkpoint_path(output);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut file = BufWriter::new(file);
+        if is_new {
+            writeln!(file, "{}", HEADER)?;
+            writeln!(file, "{}", settings(args)?)?;
+        }
+        Ok(Self {
+            file,
+            path,
+            interval,
+            last_sync: Instant::now(),
+            sorted: args.sort(),
+            last: None,
+        })
+    }
+
+    // Entries that came out of `resumed` unchanged are already in the
+    // journal, so they aren't written again.
+    pub fn record(&mut self, path: &str, entry: &Entry, resumed: Option<&Resumed>) -> Result<()> {
+        if self.sorted {
+            self.last = Some(path.to_string());
+        }
+        if !resumed.is_some_and(|resumed| resumed.has(path, entry)) {
+            match &entry.stamp {
+                Some(stamp) if entry.status.is_hashed() => {
+                    write_stamp(&mut self.file, stamp)?;
+                    write!(self.file, "\t")?;
+                    write_manifest_line(&mut self.file, path, entry)?;
+                }
+                _ if self.sorted => {
+                    write!(self.file, "-\t")?;
+                    write_manifest_line(&mut self.file, path, entry)?;
+                }
+                _ => {}
+            }
+        }
+        if self.last_sync.elapsed() >= self.interval {
+            self.sync_now()?;
+        }
+        Ok(())
+    }
+
+    fn sync_now(&mut self) -> Result<()> {
+        if let Some(last) = &self.last {
+            writeln!(self.file, "{}{}", DONE, last)?;
+        }
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    // Called when a run is interrupted, so that everything recorded so far
+    // survives for --resume.
+    pub fn sync(mut self) -> Result<()> {
+        self.sync_now()
+    }
+
+    // Called once the manifest is safely in place, at which point the
+    // checkpoint has nothing left to offer.
+    pub fn finish(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to remove {}", self.path.display()))
+    }
+}
+
+// The hashes a previous run got through, keyed like the manifest.
+#[derive(Default)]
+pub struct Resumed {
+    entries: HashMap<String, (FileStamp, String)>,
+    // Files a sorted run couldn't hash, and the last file it got through.
+    retry: HashSet<String>,
+    done: Option<String>,
+}
+
+impl Resumed {
+    // A missing checkpoint isn't an error, since the previous run may have
+    // died before writing anything. We just start from scratch. One from a
+    // run with different settings is, since resuming would mix its hashes
+    // into this manifest.
+    pub fn load(output: &Path, args: &Args) -> Result<Self> {
+        let path = checkpoint_path(output);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!(
+                    "{}: no checkpoint at {}, starting from scratch",
+                    NAME,
+                    path.display()
+                );
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let found = match lines.next().transpose()? {
+            Some(header) if header == HEADER => lines.next().transpose()?.unwrap_or_default(),
+            Some(header) if header == HEADER_V1 => V1_SETTINGS.to_string(),
+            _ => bail!("{} is not a dirhash checkpoint", path.display()),
+        };
+        let expected = settings(args)?;
+        if found != expected {
+            bail!(
+                "{} was written with different settings ({}, but this run has {}). \
+                 Rerun without --resume to start over.",
+                path.display(),
+                found.trim_start_matches("# settings "),
+                expected.trim_start_matches("# settings ")
+            );
+        }
+        let mut resumed = Self::default();
+        for line in lines {
+            let line = line?;
+            if let Some(done) = line.strip_prefix(DONE) {
+                resumed.done = Some(done.to_string());
+                continue;
+            }
+            // The last line may have been torn by whatever interrupted the
+            // run. Anything unparseable is just hashed again. Later lines for
+            // the same file win.
+            let (stamp, rest) = match line.split_once('\t') {
+                Some(split) => split,
+                None => continue,
+            };
+            let (key, value) = match parse_manifest_line(rest) {
+                Ok(parsed) => parsed,
+                Err(_) => continue,
+            };
+            if stamp == "-" {
+                resumed.entries.remove(key);
+                resumed.retry.insert(key.to_string());
+            } else if let Some(stamp) = parse_stamp(stamp) {
+                resumed.retry.remove(key);
+                resumed
+                    .entries
+                    .insert(key.to_string(), (stamp, value.to_string()));
+            }
+        }
+        if !resumed.entries.is_empty() {
+            eprintln!(
+                "{}: resuming with {} files already hashed; unchanged ones won't be hashed again",
+                NAME,
+                resumed.entries.len()
+            );
+        }
+        Ok(resumed)
+    }
+
+    // Whether the journal already holds this entry for `key`.
+    fn has(&self, key: &str, entry: &Entry) -> bool {
+        match &entry.status {
+            Status::Hashed(hash) => self
+                .entries
+                .get(key)
+                .is_some_and(|(stamp, found)| found == hash && entry.stamp.as_ref() == Some(stamp)),
+            _ => self.retry.contains(key),
+        }
+    }
+
+    // Where a sorted run got to, if this one can skip ahead to it: every
+    // file up to this path is replayed from the journal instead of walked.
+    // A --files-from list may not be the same as last time, so only -i trees
+    // are skipped.
+    pub fn done(&self, args: &Args) -> Option<PathBuf> {
+        if !args.sort() || args.files_from().is_some() {
+            return None;
+        }
+        unescape(self.done.as_ref()?).ok().map(PathBuf::from)
+    }
+
+    // The journal's files up to `done`, under the -i inputs, in walk order.
+    // Hashed ones are taken as they were, without a stat, and the rest come
+    // without an entry, to be hashed again.
+    pub fn replay(&self, args: &Args, done: &Path) -> Vec<(PathBuf, String, Option<Entry>)> {
+        let hashed = self.entries.iter().map(|(key, (stamp, hash))| {
+            let entry = Entry {
+                status: Status::Hashed(hash.clone()),
+                retries: 0,
+                stamp: Some(stamp.clone()),
+            };
+            (key, Some(entry))
+        });
+        let retry = self.retry.iter().map(|key| (key, None));
+        let mut replayed: Vec<_> = hashed
+            .chain(retry)
+            .filter_map(|(key, entry)| {
+                let path = PathBuf::from(unescape(key).ok()?);
+                let wanted = path.as_path() <= done
+                    && args.file_args.iter().any(|root| path.starts_with(root));
+                wanted.then(|| (path, key.clone(), entry))
+            })
+            .collect();
+        replayed.sort_by(|a, b| a.0.cmp(&b.0));
+        replayed
+    }
+
+    // Returns the checkpointed entry for `path`, if the file hasn't changed
+    // since it was hashed.
+    pub fn reuse(&self, path: &Path) -> Option<Entry> {
+        let (stamp, hash) = self.entries.get(&filepath_to_string(path))?;
+        let current = FileStamp::of(path).ok()??;
+        if current != *stamp {
+            return None;
+        }
+        Some(Entry {
+            status: Status::Hashed(hash.clone()),
+            retries: 0,
+            stamp: Some(current),
+        })
+    }
+}
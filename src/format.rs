@@ -0,0 +1,420 @@
+//! `--format json|jsonl`: machine-readable manifests and reports. The schema
+//! is documented in the README, and changes to it bump the version number in
+//! the `dirhash_manifest` / `dirhash_report` field.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::algo::Algo;
+use crate::html::Html;
+use crate::junit;
+use crate::{escape_filepath, unescape, Entry, Status};
+
+const VERSION: u64 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    // A single JSON document, with entries or results in an array.
+    Json,
+    // One JSON object per line.
+    Jsonl,
+    // JUnit XML, for reports only.
+    Junit,
+    // `hash  path` lines, as written by sha256sum and friends, for manifests
+    // only.
+    Gnu,
+    // `SHA256 (path) = hash` lines, as written by `sha256sum --tag`, for
+    // manifests only.
+    Bsd,
+    // hashdeep's CSV, for manifests only.
+    Hashdeep,
+    // An mtree(8) spec, for manifests only.
+    Mtree,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 7] =
+        ["text", "json", "jsonl", "gnu", "bsd", "hashdeep", "mtree"];
+    pub const REPORT_NAMES: [&'static str; 4] = ["text", "json", "jsonl", "junit"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+            Self::Junit => "junit",
+            Self::Gnu => "gnu",
+            Self::Bsd => "bsd",
+            Self::Hashdeep => "hashdeep",
+            Self::Mtree => "mtree",
+        }
+    }
+
+    pub fn from_name(name: Option<&str>) -> Self {
+        match name {
+            Some("json") => Self::Json,
+            Some("jsonl") => Self::Jsonl,
+            Some("junit") => Self::Junit,
+            Some("gnu") => Self::Gnu,
+            Some("bsd") => Self::Bsd,
+            Some("hashdeep") => Self::Hashdeep,
+            Some("mtree") => Self::Mtree,
+            _ => Self::Text,
+        }
+    }
+}
+
+// Manifest keys are escaped paths, but JSON can hold any string, so paths go
+// in unescaped.
+pub fn key_to_path(key: &str) -> String {
+    if key.contains('\\') {
+        unescape(key).unwrap_or_else(|_| key.to_string())
+    } else {
+        key.to_string()
+    }
+}
+
+fn entry_json(path: &Path, key: &str, entry: &Entry, algos: &[Algo]) -> Value {
+    let (status, hash, error) = match &entry.status {
+        Status::Hashed(hash) => ("ok", Some(hash.as_str()), None),
+        Status::Unstable => ("unstable", None, None),
+        Status::Error(kind) => ("error", None, Some(kind.as_str())),
+    };
+    // Size and mtime come from the stamp taken around the hash when there is
+    // one, so that they describe the contents that were hashed.
+    let metadata = if path == Path::new("-") {
+        None
+    } else {
+        fs::metadata(path).ok()
+    };
+    let file_type = metadata.as_ref().map(|metadata| {
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            "file"
+        } else if file_type.is_dir() {
+            "dir"
+        } else {
+            "other"
+        }
+    });
+    let stamp = entry.stamp.as_ref();
+    let size = stamp
+        .map(|stamp| stamp.len)
+        .or_else(|| metadata.as_ref().map(|metadata| metadata.len()));
+    let mtime = stamp
+        .and_then(|stamp| stamp.modified)
+        .or_else(|| metadata.and_then(|metadata| metadata.modified().ok()))
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|since| since.as_secs());
+    let mut value = json!({
+        "path": key_to_path(key),
+        "status": status,
+        "hash": hash,
+        "error": error,
+        "type": file_type,
+        "size": size,
+        "mtime": mtime,
+        "retries": entry.retries,
+    });
+    // With several algorithms, `hash` holds them all joined with commas, as
+    // in a text manifest, and `hashes` has them by name.
+    if algos.len() > 1 {
+        let hashes = hash.map(|hash| {
+            let digests = algos.iter().map(|algo| algo.name()).zip(hash.split(','));
+            digests
+                .map(|(name, digest)| (name.to_string(), Value::from(digest)))
+                .collect::<Map<_, _>>()
+        });
+        value["hashes"] = hashes.map_or(Value::Null, Value::Object);
+    }
+    value
+}
+
+fn write_json(mut w: impl Write, value: &Value) -> io::Result<()> {
+    serde_json::to_writer(&mut w, value)?;
+    writeln!(w)
+}
+
+// Writes a JSON or JSON Lines manifest an entry at a time. A JSON manifest is
+// still written one entry per line, between a header and a footer line, so
+// that a crash leaves everything up to the footer behind.
+pub struct ManifestWriter {
+    format: Format,
+    algos: Vec<Algo>,
+    total: u64,
+    failed: u64,
+}
+
+impl ManifestWriter {
+    // With several algorithms, `algorithm` lists them with commas and `length`
+    // is null, since each has its own default.
+    pub fn start(mut w: impl Write, format: Format, algos: &[Algo], length: usize) -> Result<Self> {
+        if format == Format::Json {
+            let names: Vec<&str> = algos.iter().map(|algo| algo.name()).collect();
+            let length = if algos.len() > 1 {
+                Value::Null
+            } else {
+                length.into()
+            };
+            writeln!(
+                w,
+                r#"{{"dirhash_manifest":{},"algorithm":"{}","length":{},"entries":["#,
+                VERSION,
+                names.join(","),
+                length
+            )?;
+        }
+        Ok(Self {
+            format,
+            algos: algos.to_vec(),
+            total: 0,
+            failed: 0,
+        })
+    }
+
+    pub fn entry(
+        &mut self,
+        mut w: impl Write,
+        path: &Path,
+        key: &str,
+        entry: &Entry,
+    ) -> Result<()> {
+        if self.format == Format::Json && self.total > 0 {
+            write!(w, ",")?;
+        }
+        self.total += 1;
+        if !entry.status.is_hashed() {
+            self.failed += 1;
+        }
+        write_json(w, &entry_json(path, key, entry, &self.algos))?;
+        Ok(())
+    }
+
+    // An interrupted run still gets a well-formed document, marked partial.
+    pub fn finish(self, mut w: impl Write, partial: bool) -> Result<()> {
+        if self.format == Format::Json {
+            let summary = json!({
+                "total": self.total,
+                "failed": self.failed,
+                "partial": partial,
+            });
+            writeln!(w, r#"],"summary":{}}}"#, summary)?;
+        }
+        Ok(())
+    }
+}
+
+// A JSON manifest starts with `{`, and unless the first line is a whole
+// object, it's a single document rather than JSON Lines.
+pub fn detect(first_line: &str) -> Format {
+    let trimmed = first_line.trim_start();
+    if !trimmed.starts_with('{') {
+        Format::Text
+    } else if serde_json::from_str::<Value>(trimmed).is_ok() {
+        Format::Jsonl
+    } else {
+        Format::Json
+    }
+}
+
+// Turns a manifest entry back into the key and value of a text manifest line,
+// e.g. `UNSTABLE` or `ERROR(PermissionDenied)` for entries without a hash.
+pub fn parse_entry(value: &Value) -> Result<(String, String)> {
+    let field = |name| value.get(name).and_then(Value::as_str);
+    let path = field("path").context("Manifest entry has no path")?;
+    let value = match field("status") {
+        Some("ok") => field("hash")
+            .context("Manifest entry has no hash")?
+            .to_string(),
+        Some("unstable") => Status::Unstable.to_string(),
+        Some("error") => Status::Error(field("error").unwrap_or("Other").to_string()).to_string(),
+        _ => bail!("Manifest entry for {} has no valid status", path),
+    };
+    Ok((escape_filepath(path.to_string()), value))
+}
+
+// Calls `f` with each entry of a JSON or JSON Lines manifest, given its first
+// line and a reader positioned after it.
+pub fn for_each_json_entry(
+    first_line: &str,
+    rest: impl BufRead,
+    format: Format,
+    mut f: impl FnMut(&Value) -> Result<()>,
+) -> Result<()> {
+    if format == Format::Jsonl {
+        for line in std::iter::once(Ok(first_line.to_string())).chain(rest.lines()) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            f(&serde_json::from_str(&line)?)?;
+        }
+        return Ok(());
+    }
+    let document: Value =
+        serde_json::from_reader(io::Cursor::new(first_line.to_string() + "\n").chain(rest))?;
+    let entries = document
+        .get("entries")
+        .and_then(Value::as_array)
+        .context("JSON manifest has no entries")?;
+    entries.iter().try_for_each(f)
+}
+
+pub fn read_json_manifest(
+    first_line: &str,
+    rest: impl BufRead,
+    format: Format,
+) -> Result<HashMap<String, String>> {
+    let mut manifest = HashMap::new();
+    for_each_json_entry(first_line, rest, format, |entry| {
+        let (path, value) = parse_entry(entry)?;
+        manifest.insert(path, value);
+        Ok(())
+    })?;
+    Ok(manifest)
+}
+
+// One path's line in a report.
+pub struct Record {
+    pub key: String,
+    pub status: &'static str,
+    pub expected: Option<String>,
+    pub found: Option<String>,
+    pub error: Option<String>,
+    // Where a moved or copied file's contents were in the old manifest.
+    pub from: Option<String>,
+}
+
+// The results of verify, check or diff. Text reports are printed by their
+// callers, which each have their own line format, so in text mode this only
+// keeps count. JUnit needs its totals up front, and the HTML report needs the
+// whole tree, so those are written at the end.
+pub struct Report {
+    format: Format,
+    total: u64,
+    by_status: BTreeMap<&'static str, u64>,
+    records: Vec<Record>,
+    html: Option<Html>,
+}
+
+impl Report {
+    pub fn start(format: Format) -> Result<Self> {
+        if format == Format::Json {
+            println!(r#"{{"dirhash_report":{},"results":["#, VERSION);
+        }
+        Ok(Self {
+            format,
+            total: 0,
+            by_status: BTreeMap::new(),
+            records: Vec::new(),
+            html: None,
+        })
+    }
+
+    // Also writes an HTML report when the run finishes, whatever the format.
+    pub fn html(&mut self, html: Html) {
+        self.html = Some(html);
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == Format::Text
+    }
+
+    // `status` is one of ok, mismatch, missing, new, unstable or error, and
+    // with --audit, moved or copied.
+    // `expected` and `found` are manifest values: a hash, UNSTABLE or
+    // ERROR(kind).
+    pub fn record(
+        &mut self,
+        key: &str,
+        status: &'static str,
+        expected: Option<&str>,
+        found: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        self.add(Record {
+            key: key.to_string(),
+            status,
+            expected: expected.map(str::to_string),
+            found: found.map(str::to_string),
+            error: error.map(str::to_string),
+            from: None,
+        })
+    }
+
+    // A file an audit found under a new path, with `status` moved or copied.
+    // `value` is its hash, the same on both sides.
+    pub fn record_from(
+        &mut self,
+        key: &str,
+        status: &'static str,
+        from: &str,
+        value: &str,
+    ) -> Result<()> {
+        self.add(Record {
+            key: key.to_string(),
+            status,
+            expected: Some(value.to_string()),
+            found: Some(value.to_string()),
+            error: None,
+            from: Some(from.to_string()),
+        })
+    }
+
+    fn add(&mut self, record: Record) -> Result<()> {
+        if self.format == Format::Json && self.total > 0 {
+            print!(",");
+        }
+        self.total += 1;
+        *self.by_status.entry(record.status).or_default() += 1;
+        if !matches!(self.format, Format::Text | Format::Junit) {
+            let mut result = json!({
+                "path": key_to_path(&record.key),
+                "status": record.status,
+                "expected": record.expected,
+                "found": record.found,
+                "error": record.error,
+            });
+            // Only audits have a `from`, so other reports don't change shape.
+            if let Some(from) = &record.from {
+                result["from"] = key_to_path(from).into();
+            }
+            write_json(io::stdout().lock(), &result)?;
+        }
+        if self.format == Format::Junit || self.html.is_some() {
+            self.records.push(record);
+        }
+        Ok(())
+    }
+
+    // Only JUnit and HTML reports keep their records.
+    #[cfg(test)]
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn finish(self, partial: bool) -> Result<()> {
+        let mut summary = Map::new();
+        summary.insert("total".into(), self.total.into());
+        for (status, count) in self.by_status {
+            summary.insert(status.into(), count.into());
+        }
+        summary.insert("partial".into(), partial.into());
+        match self.format {
+            Format::Text | Format::Gnu | Format::Bsd | Format::Hashdeep | Format::Mtree => {}
+            Format::Json => println!(r#"],"summary":{}}}"#, Value::Object(summary)),
+            Format::Jsonl => println!("{}", json!({ "summary": summary })),
+            Format::Junit => junit::write(io::stdout().lock(), &self.records, partial)?,
+        }
+        if let Some(html) = self.html {
+            html.write(&self.records, partial)?;
+        }
+        Ok(())
+    }
+}
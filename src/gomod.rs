@@ -0,0 +1,275 @@
+//! `dirhash gomod`: Go's `h1:` module hashes, as recorded in go.sum, computed
+//! the way `golang.org/x/mod/sumdb/dirhash.Hash1` does. That's the SHA-256 of
+//! a sha256sum-style listing of every file in the module:
+//!
+//! ```text
+//! 2d5...c1e  golang.org/x/mod@v0.1.0/LICENSE
+//! 9a1...07f  golang.org/x/mod@v0.1.0/go.mod
+//! ```
+//!
+//! with the names sorted as byte strings, then base64-encoded after `h1:`.
+//! It takes a module directory, a module zip, or a lone go.mod file, which
+//! go.sum records on its own `/go.mod` line.
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::Engine;
+use sha2::{Digest as _, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::walk::{self, Walked};
+use crate::{hash_one_input, Args, Status};
+
+// Each file's name and its SHA-256 in hex.
+type Listing = Vec<(String, String)>;
+
+fn hash1(mut files: Listing) -> Result<String> {
+    files.sort();
+    let mut summary = Sha256::new();
+    for (name, hex) in &files {
+        ensure!(
+            !name.contains('\n'),
+            "{:?}: Go module hashes can't include names with newlines",
+            name
+        );
+        summary.update(format!("{}  {}\n", hex, name));
+    }
+    let encoded = base64::engine::general_purpose::STANDARD.encode(summary.finalize());
+    Ok(format!("h1:{}", encoded))
+}
+
+// Like dirhash.DirFiles, everything but directories is listed, symlinks
+// included, under `prefix/`. Go opens symlinks, so we hash what they point
+// to.
+fn list_dir(args: &'static Args, dir: &Path, prefix: &str) -> Result<Listing> {
+    let mut files = Vec::new();
+    for walked in walk::walk_tree(args, dir) {
+        let path = match walked? {
+            Walked::File(path) => path,
+            Walked::Other(path) if !fs::symlink_metadata(&path)?.is_dir() => path,
+            Walked::Other(_) => continue,
+            Walked::Error(e) => bail!(e),
+        };
+        let rel = walk::relative_slash_path(dir, &path)?;
+        let entry = hash_one_input(&path, args);
+        match entry.status {
+            Status::Hashed(hex) => files.push((format!("{}/{}", prefix, rel), hex)),
+            // hash_one_input() already printed the error.
+            status => bail!("{} couldn't be hashed: {}", path.display(), status),
+        }
+    }
+    Ok(files)
+}
+
+// Every entry in the zip, under the name it has there. Module zips already
+// put everything under `module@version/`.
+fn list_zip(path: &Path) -> Result<Listing> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut zip = zip::ZipArchive::new(BufReader::new(file))
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut files = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut entry, &mut hasher)
+            .with_context(|| format!("Failed to read {} in {}", entry.name(), path.display()))?;
+        let hex = hex::encode(hasher.finalize());
+        files.push((entry.name().to_string(), hex));
+    }
+    Ok(files)
+}
+
+// The `module@version` a zip's entries are under, for looking it up in
+// go.sum. Module paths have slashes in them, so it runs up to the first slash
+// after the `@`.
+fn zip_prefix(files: &Listing) -> Option<String> {
+    let name = &files.first()?.0;
+    let at = name.find('@')?;
+    let end = at + name[at..].find('/')?;
+    Some(name[..end].to_string())
+}
+
+// go.sum lines are `module version h1:hash`, with `version/go.mod` for the
+// hash of just the go.mod file.
+fn go_sum_hash(go_sum: &Path, module: &str, version: &str) -> Result<Option<String>> {
+    let file =
+        File::open(go_sum).with_context(|| format!("Failed to open {}", go_sum.display()))?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [m, v, hash] = fields[..] {
+            if m == module && v == version && hash.starts_with("h1:") {
+                return Ok(Some(hash.to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Prints the h1: hash of the module, or with --go-sum, checks it against the
+// module's line there. Returns true if it doesn't match.
+pub fn main(args: &'static Args) -> Result<bool> {
+    let path = args.gomod_path();
+    let is_zip = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    let (hash, prefix, go_mod_only) = if path.is_dir() {
+        let prefix = args
+            .gomod_prefix()
+            .context("Hashing a module directory needs --prefix module@version")?;
+        (
+            hash1(list_dir(args, path, prefix)?)?,
+            Some(prefix.to_string()),
+            false,
+        )
+    } else if is_zip {
+        let files = list_zip(path)?;
+        let prefix = args
+            .gomod_prefix()
+            .map(str::to_string)
+            .or_else(|| zip_prefix(&files));
+        (hash1(files)?, prefix, false)
+    } else {
+        let entry = hash_one_input(path, args);
+        let hex = match entry.status {
+            Status::Hashed(hex) => hex,
+            status => bail!("{} couldn't be hashed: {}", path.display(), status),
+        };
+        let files = vec![("go.mod".to_string(), hex)];
+        (hash1(files)?, args.gomod_prefix().map(str::to_string), true)
+    };
+
+    let go_sum = match args.go_sum_path() {
+        Some(go_sum) => go_sum,
+        None => {
+            println!("{}", hash);
+            return Ok(false);
+        }
+    };
+    let prefix = prefix.context("--go-sum needs --prefix module@version to find the module")?;
+    let (module, version) = prefix
+        .rsplit_once('@')
+        .with_context(|| format!("--prefix {:?} should look like module@version", prefix))?;
+    let version = if go_mod_only {
+        format!("{}/go.mod", version)
+    } else {
+        version.to_string()
+    };
+    let expected = go_sum_hash(go_sum, module, &version)?.with_context(|| {
+        format!(
+            "{} has no h1: line for {} {}",
+            go_sum.display(),
+            module,
+            version
+        )
+    })?;
+    if expected == hash {
+        println!("{}: OK", path.display());
+        Ok(false)
+    } else {
+        println!(
+            "{}: MISMATCH (go.sum has {}, found {})",
+            path.display(),
+            expected,
+            hash
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(data: &str) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    // TestHash1 in golang.org/x/mod/sumdb/dirhash: files "xyz" and "abc"
+    // holding "data for <name>". The listing is sorted, so abc comes first.
+    #[test]
+    fn hash1_matches_go() {
+        let files = ["xyz", "abc"]
+            .map(|name| (name.to_string(), sha256_hex(&format!("data for {}", name))))
+            .to_vec();
+        let listing = format!(
+            "{}  abc\n{}  xyz\n",
+            sha256_hex("data for abc"),
+            sha256_hex("data for xyz")
+        );
+        let want = base64::engine::general_purpose::STANDARD.encode(Sha256::digest(listing));
+        assert_eq!(hash1(files.clone()).unwrap(), format!("h1:{}", want));
+        assert_eq!(
+            hash1(files).unwrap(),
+            "h1:EP6WfDoQTSk9klnxcjriWJOGztueJUywHNmWeq+efB8="
+        );
+    }
+
+    // The go.mod Go synthesizes for golang.org/x/text v0.3.0, which predates
+    // modules, and its line in countless go.sum files.
+    #[test]
+    fn hash1_of_a_go_mod() {
+        let files = vec![(
+            "go.mod".to_string(),
+            sha256_hex("module golang.org/x/text\n"),
+        )];
+        assert_eq!(
+            hash1(files).unwrap(),
+            "h1:NqM8EUOU14njkJ3fqMW+pc6Ldnwhi/IjpwHt7yyuwOQ="
+        );
+    }
+
+    #[test]
+    fn hash1_rejects_newlines() {
+        let files = vec![("a\nb".to_string(), sha256_hex(""))];
+        assert!(hash1(files).is_err());
+    }
+
+    #[test]
+    fn zip_prefix_runs_to_the_slash_after_the_version() {
+        let files = vec![
+            ("golang.org/x/mod@v0.1.0/LICENSE".to_string(), String::new()),
+            ("golang.org/x/mod@v0.1.0/go.mod".to_string(), String::new()),
+        ];
+        assert_eq!(
+            zip_prefix(&files).as_deref(),
+            Some("golang.org/x/mod@v0.1.0")
+        );
+        let files = vec![(
+            "example.com/a/b@v1.2.3-pre/sub/f.go".to_string(),
+            String::new(),
+        )];
+        assert_eq!(
+            zip_prefix(&files).as_deref(),
+            Some("example.com/a/b@v1.2.3-pre")
+        );
+        let files = vec![("no/version/here".to_string(), String::new())];
+        assert_eq!(zip_prefix(&files), None);
+        assert_eq!(zip_prefix(&Vec::new()), None);
+    }
+
+    #[test]
+    fn finds_go_sum_lines() {
+        let go_sum = std::env::temp_dir().join(format!("dirhash-go-sum-{}", std::process::id()));
+        fs::write(
+            &go_sum,
+            "golang.org/x/text v0.3.0 h1:g61tztE5qeGQ89tm6NTjjM9VPIm088od1l6aSorWRWg=\n\
+             golang.org/x/text v0.3.0/go.mod h1:NqM8EUOU14njkJ3fqMW+pc6Ldnwhi/IjpwHt7yyuwOQ=\n\
+             golang.org/x/mod v0.1.0 h2:notthekindwewant=\n",
+        )
+        .unwrap();
+        let find = |module, version| go_sum_hash(&go_sum, module, version).unwrap();
+        assert_eq!(
+            find("golang.org/x/text", "v0.3.0").as_deref(),
+            Some("h1:g61tztE5qeGQ89tm6NTjjM9VPIm088od1l6aSorWRWg=")
+        );
+        assert_eq!(
+            find("golang.org/x/text", "v0.3.0/go.mod").as_deref(),
+            Some("h1:NqM8EUOU14njkJ3fqMW+pc6Ldnwhi/IjpwHt7yyuwOQ=")
+        );
+        assert_eq!(find("golang.org/x/mod", "v0.1.0"), None);
+        assert_eq!(find("golang.org/x/text", "v0.3.1"), None);
+        fs::remove_file(&go_sum).unwrap();
+    }
+}
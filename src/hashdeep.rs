@@ -0,0 +1,224 @@
+//! hashdeep's CSV manifests, for `hash --format hashdeep` and for reading
+//! them back in verify and diff. A file looks like this:
+//!
+//! ```text
+//! %%%% HASHDEEP-1.0
+//! %%%% size,md5,sha256,filename
+//! ## Invoked from: /home/user
+//! ## $ hashdeep -r photos
+//! ##
+//! 1024,0cc1...,5e88...,photos/a.jpg
+//! ```
+
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::algo::Algo;
+use crate::format::key_to_path;
+use crate::{escape_filepath, Entry, Status, NAME};
+
+pub const HEADER: &str = "%%%% HASHDEEP-1.0";
+
+pub fn write_header(mut w: impl Write, algos: &[Algo]) -> io::Result<()> {
+    let names: Vec<&str> = algos.iter().map(|algo| algo.name()).collect();
+    writeln!(w, "{}", HEADER)?;
+    writeln!(w, "%%%% size,{},filename", names.join(","))?;
+    if let Ok(dir) = env::current_dir() {
+        writeln!(w, "## Invoked from: {}", dir.display())?;
+    }
+    let command: Vec<String> = env::args().collect();
+    writeln!(w, "## $ {}", command.join(" "))?;
+    writeln!(w, "##")
+}
+
+// hashdeep doesn't quote or escape filenames, so a name with a newline in it
+// can't be written at all. Like files that couldn't be hashed, those are left
+// out, with a warning.
+pub fn write_entry(mut w: impl Write, path: &Path, key: &str, entry: &Entry) -> io::Result<()> {
+    let hashes = match &entry.status {
+        Status::Hashed(hashes) => hashes,
+        _ => return Ok(()),
+    };
+    let filename = key_to_path(key);
+    if filename.contains('\n') {
+        eprintln!(
+            "{}: {}: hashdeep can't record names with newlines",
+            NAME, key
+        );
+        return Ok(());
+    }
+    let size = entry
+        .stamp
+        .as_ref()
+        .map(|stamp| stamp.len)
+        .or_else(|| fs::metadata(path).ok().map(|metadata| metadata.len()))
+        .unwrap_or(0);
+    writeln!(w, "{},{},{}", size, hashes, filename)
+}
+
+// Reads the entries after the header line into manifest keys and values. A
+// value is the hashes for `algos`, joined with commas as in our own
+// manifests. For diff, which has no --algo, it's every hash column.
+pub fn read(mut rest: impl BufRead, algos: Option<&[Algo]>) -> Result<HashMap<String, String>> {
+    let mut columns_line = String::new();
+    rest.read_line(&mut columns_line)?;
+    let columns: Vec<&str> = columns_line
+        .trim_end_matches('\n')
+        .strip_prefix("%%%% ")
+        .context("hashdeep manifest has no column line")?
+        .split(',')
+        .collect();
+    ensure!(
+        columns.len() >= 3 && columns[0] == "size" && columns[columns.len() - 1] == "filename",
+        "Unexpected hashdeep columns"
+    );
+    let hash_columns = &columns[1..columns.len() - 1];
+    let wanted: Vec<usize> = match algos {
+        Some(algos) => algos
+            .iter()
+            .map(|algo| {
+                hash_columns
+                    .iter()
+                    .position(|name| *name == algo.name())
+                    .with_context(|| format!("hashdeep manifest has no {} column", algo.name()))
+            })
+            .collect::<Result<_>>()?,
+        None => (0..hash_columns.len()).collect(),
+    };
+    let mut manifest = HashMap::new();
+    for line in rest.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("%%%%") {
+            continue;
+        }
+        // Filenames may contain commas, so everything after the hashes is the
+        // filename.
+        let fields: Vec<&str> = line.splitn(columns.len(), ',').collect();
+        if fields.len() != columns.len() {
+            bail!("Short line in hashdeep manifest: {}", line);
+        }
+        let hashes = &fields[1..fields.len() - 1];
+        let value: Vec<&str> = wanted.iter().map(|&i| hashes[i]).collect();
+        let filename = fields[fields.len() - 1].to_string();
+        manifest.insert(escape_filepath(filename), value.join(","));
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileStamp;
+
+    const MD5: &str = "0cc175b9c0f1b6a831c399e269772661";
+    const SHA1: &str = "86f7e437faa5a7fce15d1ddcb9eaeaea377667b8";
+    const SHA256: &str = "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb";
+
+    // As written by `hashdeep -c md5,sha256 -r photos`, with a comma and a
+    // backslash in two of the names.
+    fn hashdeep_file() -> String {
+        format!(
+            "%%%% HASHDEEP-1.0\n\
+             %%%% size,md5,sha256,filename\n\
+             ## Invoked from: /home/user\n\
+             ## $ hashdeep -c md5,sha256 -r photos\n\
+             ##\n\
+             1,{md5},{sha},photos/a.jpg\n\
+             1,{md5},{sha},photos/b, c.jpg\n\
+             1,{md5},{sha},photos/d\\e.jpg\n",
+            md5 = MD5,
+            sha = SHA256
+        )
+    }
+
+    fn read_file(text: &str, algos: Option<&[Algo]>) -> Result<HashMap<String, String>> {
+        let mut reader = text.as_bytes();
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        assert_eq!(header.trim_end(), HEADER);
+        read(reader, algos)
+    }
+
+    #[test]
+    fn reads_the_wanted_columns() {
+        let text = hashdeep_file();
+        let manifest = read_file(&text, Some(&[Algo::Sha256])).unwrap();
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(manifest["photos/a.jpg"], SHA256);
+        assert_eq!(manifest["photos/b, c.jpg"], SHA256);
+        assert_eq!(manifest["photos/d\\\\e.jpg"], SHA256);
+
+        // diff takes every hash column.
+        let manifest = read_file(&text, None).unwrap();
+        assert_eq!(manifest["photos/a.jpg"], format!("{},{}", MD5, SHA256));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let text = hashdeep_file();
+        assert!(read_file(&text, Some(&[Algo::Blake3])).is_err());
+        let short = text.replace(
+            &format!("{},{},photos/a.jpg", MD5, SHA256),
+            "1,photos/a.jpg",
+        );
+        assert!(read_file(&short, None).is_err());
+        let no_columns = format!("{}\n1,{},a\n", HEADER, SHA256);
+        assert!(read_file(&no_columns, None).is_err());
+    }
+
+    #[test]
+    fn writes_entries_that_read_back() {
+        let entry = |status| Entry {
+            status,
+            retries: 0,
+            stamp: Some(FileStamp {
+                len: 42,
+                modified: None,
+                changed: (0, 0),
+                inode: (0, 0),
+            }),
+        };
+        let hashes = format!("{},{}", SHA1, SHA256);
+        let mut written = Vec::new();
+        write_header(&mut written, &[Algo::Sha1, Algo::Sha256]).unwrap();
+        let path = Path::new("unused");
+        write_entry(
+            &mut written,
+            path,
+            "b, c",
+            &entry(Status::Hashed(hashes.clone())),
+        )
+        .unwrap();
+        write_entry(
+            &mut written,
+            path,
+            "d\\\\e",
+            &entry(Status::Hashed(hashes.clone())),
+        )
+        .unwrap();
+        // Left out: a name hashdeep can't hold, and files with no hash.
+        write_entry(
+            &mut written,
+            path,
+            "new\\nline",
+            &entry(Status::Hashed(hashes.clone())),
+        )
+        .unwrap();
+        write_entry(&mut written, path, "unstable", &entry(Status::Unstable)).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        assert!(text.contains(&format!("\n42,{},b, c\n", hashes)));
+        assert!(text.contains(&format!("\n42,{},d\\e\n", hashes)));
+
+        let manifest = read_file(&text, None).unwrap();
+        let mut keys: Vec<&String> = manifest.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["b, c", "d\\\\e"]);
+        assert_eq!(manifest["b, c"], hashes);
+        let manifest = read_file(&text, Some(&[Algo::Sha256])).unwrap();
+        assert_eq!(manifest["d\\\\e"], SHA256);
+    }
+}
@@ -0,0 +1,381 @@
+//! `--html`: a single self-contained HTML page showing what changed between
+//! a manifest and a tree (verify) or two manifests (diff), for reviewers who
+//! would rather not read the text report.
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::escape_filepath;
+use crate::format::{self, key_to_path, Format, Record};
+use crate::output::Output;
+use crate::progress::format_bytes;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Meta {
+    size: Option<u64>,
+    mtime: Option<u64>,
+}
+
+// Where a side of the comparison gets sizes and mtimes from. Text manifests
+// don't record them, so they're only shown when they're known.
+pub enum Metadata {
+    Manifest(HashMap<String, Meta>),
+    Disk,
+}
+
+impl Metadata {
+    // Reads sizes and mtimes from a JSON manifest. A text manifest gives
+    // an empty map.
+    pub fn from_manifest(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut reader = BufReader::new(file);
+        let mut first_line = String::new();
+        reader.read_line(&mut first_line)?;
+        let first_line = first_line.trim_end_matches('\n');
+        let mut map = HashMap::new();
+        let format = format::detect(first_line);
+        if format != Format::Text {
+            format::for_each_json_entry(first_line, reader, format, |entry| {
+                if let Some(path) = entry.get("path").and_then(Value::as_str) {
+                    let meta = Meta {
+                        size: entry.get("size").and_then(Value::as_u64),
+                        mtime: entry.get("mtime").and_then(Value::as_u64),
+                    };
+                    map.insert(escape_filepath(path.to_string()), meta);
+                }
+                Ok(())
+            })
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        }
+        Ok(Self::Manifest(map))
+    }
+
+    fn get(&self, key: &str) -> Option<Meta> {
+        match self {
+            Self::Manifest(map) => map.get(key).copied(),
+            Self::Disk => {
+                let metadata = fs::metadata(key_to_path(key)).ok()?;
+                Some(Meta {
+                    size: Some(metadata.len()),
+                    mtime: metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map(|since| since.as_secs()),
+                })
+            }
+        }
+    }
+}
+
+pub struct Html {
+    path: PathBuf,
+    title: String,
+    old: Metadata,
+    new: Metadata,
+}
+
+// A file worth showing: anything other than an unchanged match.
+struct Change<'a> {
+    name: String,
+    class: &'static str,
+    record: &'a Record,
+    old: Option<Meta>,
+    new: Option<Meta>,
+}
+
+#[derive(Default)]
+struct Dir<'a> {
+    dirs: BTreeMap<String, Dir<'a>>,
+    files: Vec<Change<'a>>,
+    changed: u64,
+    unchanged: u64,
+}
+
+impl<'a> Dir<'a> {
+    fn insert(&mut self, components: &[&str], change: Option<Change<'a>>) {
+        if change.is_some() {
+            self.changed += 1;
+        } else {
+            self.unchanged += 1;
+        }
+        match components {
+            [] | [_] => self.files.extend(change),
+            [dir, rest @ ..] => self
+                .dirs
+                .entry(dir.to_string())
+                .or_default()
+                .insert(rest, change),
+        }
+    }
+}
+
+impl Html {
+    pub fn new(path: &Path, title: String, old: Metadata, new: Metadata) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            title,
+            old,
+            new,
+        }
+    }
+
+    // Unchanged files aren't listed, only counted per directory, so the page
+    // stays small however big the tree is. Files whose hash matches but whose
+    // size or mtime moved are listed as metadata changes.
+    pub fn write(self, records: &[Record], partial: bool) -> Result<()> {
+        let mut root = Dir::default();
+        let mut counts: BTreeMap<&str, u64> = BTreeMap::new();
+        for record in records {
+            let path = key_to_path(&record.key);
+            let components: Vec<&str> = path
+                .split('/')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .collect();
+            let old = self.old.get(&record.key);
+            let new = self.new.get(&record.key);
+            let class = match record.status {
+                "new" => "added",
+                "missing" => "removed",
+                "mismatch" => "modified",
+                "changed" => "metadata",
+                "moved" => "moved",
+                "copied" => "copied",
+                "ok" => match (old, new) {
+                    (Some(old), Some(new)) if old != new => "metadata",
+                    _ => "unchanged",
+                },
+                _ => "error",
+            };
+            *counts.entry(class).or_default() += 1;
+            let change = (class != "unchanged").then(|| Change {
+                name: components.last().unwrap_or(&"").to_string(),
+                class,
+                record,
+                old,
+                new,
+            });
+            root.insert(&components, change);
+        }
+        let mut page = String::new();
+        write_page(&mut page, &self.title, &counts, &root, partial);
+        // Through a temporary file like a manifest, so that a failed write
+        // doesn't leave a truncated report where the last one was.
+        let mut output = Output::open(Some(&self.path))?;
+        output.write_all(page.as_bytes())?;
+        output.finish(false)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+const STYLE: &str = "
+body { font: 14px/1.4 system-ui, sans-serif; margin: 2em; color: #222; }
+h1 { font-size: 1.4em; }
+ul { list-style: none; padding-left: 1.4em; margin: 0; }
+summary { cursor: pointer; font-weight: 600; }
+.counts { font-weight: normal; color: #666; }
+.tag { display: inline-block; min-width: 6.5em; font-size: 0.8em; font-weight: 600; }
+.meta, .hash { color: #555; font-size: 0.9em; margin-left: 1em; }
+.hash { font-family: monospace; }
+.added .tag { color: #1a7f37; } .added { background: #e6ffec; }
+.removed .tag { color: #cf222e; } .removed { background: #ffebe9; }
+.modified .tag { color: #9a6700; } .modified { background: #fff8c5; }
+.metadata .tag { color: #0969da; }
+.moved .tag, .copied .tag { color: #0969da; } .moved, .copied { background: #ddf4ff; }
+.error .tag { color: #8250df; } .error { background: #fbefff; }
+.summary span { margin-right: 1.5em; }
+.partial { color: #cf222e; font-weight: 600; }
+";
+
+const SCRIPT: &str = "
+function setAll(open) {
+  document.querySelectorAll('details').forEach(function (d) { d.open = open; });
+}
+";
+
+fn write_page(
+    page: &mut String,
+    title: &str,
+    counts: &BTreeMap<&str, u64>,
+    root: &Dir,
+    partial: bool,
+) {
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title>\
+         <style>{1}</style><script>{2}</script></head>\n<body><h1>{0}</h1>\n",
+        escape(title),
+        STYLE,
+        SCRIPT
+    );
+    if partial {
+        page.push_str(
+            "<p class=\"partial\">The run was interrupted, so this report is partial.</p>\n",
+        );
+    }
+    page.push_str("<p class=\"summary\">");
+    for class in [
+        "added",
+        "removed",
+        "modified",
+        "moved",
+        "copied",
+        "metadata",
+        "error",
+        "unchanged",
+    ] {
+        let _ = write!(
+            page,
+            "<span class=\"{}\">{}: {}</span>",
+            class,
+            class,
+            counts.get(class).unwrap_or(&0)
+        );
+    }
+    page.push_str(
+        "</p>\n<p><button onclick=\"setAll(true)\">Expand all</button> \
+         <button onclick=\"setAll(false)\">Collapse all</button></p>\n",
+    );
+    if root.changed == 0 {
+        page.push_str("<p>No changes.</p>\n");
+    } else {
+        page.push_str("<ul>\n");
+        write_dir_contents(page, root);
+        page.push_str("</ul>\n");
+    }
+    page.push_str("</body></html>\n");
+}
+
+// Directories without changes are left out, since they'd only repeat the
+// unchanged count.
+fn write_dir_contents(page: &mut String, dir: &Dir) {
+    for (name, child) in &dir.dirs {
+        if child.changed == 0 {
+            continue;
+        }
+        let _ = writeln!(
+            page,
+            "<li><details open><summary>{}/ <span class=\"counts\">{} changed, {} unchanged\
+             </span></summary><ul>",
+            escape(name),
+            child.changed,
+            child.unchanged
+        );
+        write_dir_contents(page, child);
+        page.push_str("</ul></details></li>\n");
+    }
+    for change in &dir.files {
+        write_change(page, change);
+    }
+}
+
+fn write_change(page: &mut String, change: &Change) {
+    let _ = write!(
+        page,
+        "<li class=\"{}\"><span class=\"tag\">{}</span>{}",
+        change.class,
+        change.class.to_uppercase(),
+        escape(&change.name)
+    );
+    let size = |meta: Option<Meta>| meta.and_then(|meta| meta.size);
+    let mtime = |meta: Option<Meta>| meta.and_then(|meta| meta.mtime);
+    let mut meta = Vec::new();
+    if let Some(from) = &change.record.from {
+        meta.push(format!("from {}", key_to_path(from)));
+    }
+    if let Some(part) = describe("size", size(change.old), size(change.new), |size| {
+        format_bytes(size as f64)
+    }) {
+        meta.push(part);
+    }
+    if let Some(part) = describe("mtime", mtime(change.old), mtime(change.new), format_time) {
+        meta.push(part);
+    }
+    if !meta.is_empty() {
+        let _ = write!(
+            page,
+            "<span class=\"meta\">{}</span>",
+            escape(&meta.join(", "))
+        );
+    }
+    let record = change.record;
+    let hash = match (&record.expected, &record.found) {
+        (Some(old), Some(new)) if old != new => format!("{} \u{2192} {}", short(old), short(new)),
+        (Some(value), _) | (None, Some(value)) => short(value),
+        (None, None) => String::new(),
+    };
+    if !hash.is_empty() {
+        let _ = write!(page, "<span class=\"hash\">{}</span>", escape(&hash));
+    }
+    page.push_str("</li>\n");
+}
+
+// `size 1.0 KiB → 1.5 KiB` if it changed, `size 1.0 KiB` otherwise.
+fn describe(
+    label: &str,
+    old: Option<u64>,
+    new: Option<u64>,
+    show: impl Fn(u64) -> String,
+) -> Option<String> {
+    match (old, new) {
+        (Some(old), Some(new)) if old != new => {
+            let (mut shown_old, mut shown_new) = (show(old), show(new));
+            // Only sizes are rounded enough to look the same, e.g. 97.7 KiB.
+            if shown_old == shown_new {
+                shown_old = format!("{} B", old);
+                shown_new = format!("{} B", new);
+            }
+            Some(format!("{} {} \u{2192} {}", label, shown_old, shown_new))
+        }
+        (Some(value), _) | (None, Some(value)) => Some(format!("{} {}", label, show(value))),
+        (None, None) => None,
+    }
+}
+
+// Hashes are long, and the first 16 digits are plenty to tell them apart.
+fn short(value: &str) -> String {
+    if value.len() > 16 && value.is_ascii() {
+        format!("{}\u{2026}", &value[..16])
+    } else {
+        value.to_string()
+    }
+}
+
+// Seconds since the Unix epoch as a UTC date and time, using Howard Hinnant's
+// days-to-civil algorithm.
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,111 @@
+//! `--report-format junit`: verify, check and diff reports as JUnit XML, so
+//! that CI dashboards show integrity regressions as failing tests.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::format::Record;
+
+impl Record {
+    fn failure_message(&self) -> String {
+        let expected = self.expected.as_deref().unwrap_or("");
+        let found = self.found.as_deref().unwrap_or("");
+        match self.status {
+            "mismatch" | "changed" => format!("expected {}, found {}", expected, found),
+            "missing" => "in the manifest, but not found".to_string(),
+            "new" => "found, but not in the manifest".to_string(),
+            "unstable" => "changed while it was being hashed".to_string(),
+            "moved" | "copied" => format!(
+                "{} from {}",
+                self.status,
+                self.from.as_deref().unwrap_or("")
+            ),
+            _ => format!(
+                "could not be hashed: {}",
+                self.error.as_deref().unwrap_or("Other")
+            ),
+        }
+    }
+}
+
+// Escapes text for attribute values. Parsers turn a literal tab or newline
+// in an attribute into a space, so those are written as character references.
+// Other control characters aren't allowed in XML 1.0 at all, even escaped, so
+// they're replaced.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c if c.is_control() => escaped.push('\u{FFFD}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Each file is a testcase, in a testsuite for the directory it's in, so that
+// dashboards can roll failures up by directory. Anything other than ok is a
+// failure, with the report status as its type.
+pub fn write(mut w: impl Write, cases: &[Record], partial: bool) -> io::Result<()> {
+    let mut suites: BTreeMap<&str, Vec<&Record>> = BTreeMap::new();
+    for case in cases {
+        let dir = Path::new(&case.key)
+            .parent()
+            .and_then(Path::to_str)
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or(".");
+        suites.entry(dir).or_default().push(case);
+    }
+    let failures = |cases: &[&Record]| cases.iter().filter(|case| case.status != "ok").count();
+    let all: Vec<&Record> = cases.iter().collect();
+    writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        w,
+        r#"<testsuites name="dirhash" tests="{}" failures="{}">"#,
+        cases.len(),
+        failures(&all)
+    )?;
+    for (dir, cases) in &suites {
+        writeln!(
+            w,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" errors="0" skipped="0">"#,
+            escape(dir),
+            cases.len(),
+            failures(cases)
+        )?;
+        for case in cases {
+            write!(
+                w,
+                r#"    <testcase classname="{}" name="{}""#,
+                escape(dir),
+                escape(&case.key)
+            )?;
+            if case.status == "ok" {
+                writeln!(w, "/>")?;
+                continue;
+            }
+            writeln!(w, ">")?;
+            writeln!(
+                w,
+                r#"      <failure type="{}" message="{}"/>"#,
+                case.status.to_uppercase(),
+                escape(&case.failure_message())
+            )?;
+            writeln!(w, "    </testcase>")?;
+        }
+        writeln!(w, "  </testsuite>")?;
+    }
+    if partial {
+        writeln!(w, "  <!-- interrupted: not every path was checked -->")?;
+    }
+    writeln!(w, "</testsuites>")
+}
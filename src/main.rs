@@ -1,20 +1,82 @@
 use anyhow::{bail, ensure, Context, Result};
 use clap::{Arg, Command};
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
 use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+mod algo;
+mod audit;
+mod bag;
+mod cargo_checksum;
+mod checkpoint;
+mod format;
+mod gomod;
+mod hashdeep;
+mod html;
+mod junit;
+mod mtree;
+mod output;
+mod progress;
+mod sorted;
+mod walk;
+
+use algo::{Algo, Digest, HashOutput, Several};
+use checkpoint::{Checkpoint, Resumed};
+use format::{Format, ManifestWriter, Report};
+use html::{Html, Metadata};
+use output::Output;
+use progress::{Progress, Scan};
+use walk::Walked;
 
 const NAME: &str = "dirhash";
 
+const HASH_CMD: &str = "hash";
+const VERIFY_CMD: &str = "verify";
+const CHECK_CMD: &str = "check";
+const DIFF_CMD: &str = "diff";
+const BAG_CMD: &str = "bag";
+const BAG_CREATE_CMD: &str = "create";
+const BAG_VALIDATE_CMD: &str = "validate";
+const GOMOD_CMD: &str = "gomod";
+const CARGO_CHECKSUM_CMD: &str = "cargo-checksum";
+
 const FILE_ARG: &str = "file";
+const FILES_FROM_ARG: &str = "files-from";
+const NULL_ARG: &str = "null";
+const SORT_ARG: &str = "sort";
+const SORTED_ARG: &str = "sorted";
+const PROGRESS_ARG: &str = "progress";
+const FORMAT_ARG: &str = "format";
+const HTML_ARG: &str = "html";
+const AUDIT_ARG: &str = "audit";
+const BAG_ARG: &str = "bag";
+const INFO_ARG: &str = "info";
+const FAST_ARG: &str = "fast";
+const MODULE_ARG: &str = "module";
+const PREFIX_ARG: &str = "prefix";
+const GO_SUM_ARG: &str = "go-sum";
+const CRATE_ARG: &str = "crate";
+const UPDATE_ARG: &str = "update";
+const ALGO_ARG: &str = "algo";
 const OUTPUT_ARG: &str = "output";
-const VERIFY_ARG: &str = "verify";
+const BACKUP_ARG: &str = "backup";
+const RESUME_ARG: &str = "resume";
+const CHECKPOINT_INTERVAL_ARG: &str = "checkpoint-interval";
+const MANIFEST_ARG: &str = "manifest";
+const CHECKFILE_ARG: &str = "checkfile";
+const OLD_ARG: &str = "old";
+const NEW_ARG: &str = "new";
 const DERIVE_KEY_ARG: &str = "derive-key";
 const KEYED_ARG: &str = "keyed";
 const LENGTH_ARG: &str = "length";
@@ -22,146 +84,670 @@ const NO_MMAP_ARG: &str = "no-mmap";
 const NO_NAMES_ARG: &str = "no-names";
 const NUM_THREADS_ARG: &str = "num-threads";
 const RAW_ARG: &str = "raw";
-const CHECK_ARG: &str = "check";
 const QUIET_ARG: &str = "quiet";
+const UNSTABLE_RETRIES_ARG: &str = "unstable-retries";
+const READ_RETRIES_ARG: &str = "read-retries";
+const TIMEOUT_ARG: &str = "timeout";
+
+// The exit status after SIGINT or SIGTERM, as a shell reports for SIGINT.
+const INTERRUPTED_STATUS: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The first SIGINT or SIGTERM lets the file being hashed finish, so that what
+// was done so far can be saved. A second one exits on the spot.
+fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(INTERRUPTED_STATUS);
+        }
+        eprintln!(
+            "{}: interrupted, finishing the current file (interrupt again to quit now)",
+            NAME
+        );
+    })
+    .context("Failed to install signal handler")
+}
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    // Hash files and directories into a manifest.
+    Hash,
+    // Rehash files and directories and compare them against a manifest.
+    Verify,
+    // Check b3sum-style checkfiles.
+    Check,
+    // Compare two manifests without hashing anything.
+    Diff,
+    // Turn a directory into a BagIt bag.
+    BagCreate,
+    // Check a BagIt bag.
+    BagValidate,
+    // Compute or check a Go module's h1: hash.
+    GoMod,
+    // Check or rewrite a vendored crate's .cargo-checksum.json.
+    CargoChecksum,
+}
+
+impl Mode {
+    // Bags, go.sum and .cargo-checksum.json have no place for a key, so only
+    // these take --keyed and --derive-key.
+    fn takes_keys(self) -> bool {
+        matches!(self, Self::Hash | Self::Verify | Self::Check)
+    }
+}
 
 struct Args {
+    // The matches for the subcommand, not the top-level command.
     inner: clap::ArgMatches,
+    mode: Mode,
     file_args: Vec<PathBuf>,
-    output_path: PathBuf,
-    base_hasher: blake3::Hasher,
-    verify: bool
+    // More than one with e.g. `--algo blake3,sha256`.
+    algos: Vec<Algo>,
+    base_hasher: Box<dyn Digest>,
+    sparse_stats: SparseStats,
+}
+
+// The arguments that say what to hash, shared by hash and verify.
+fn input_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new(FILE_ARG)
+            .short('i')
+            .long("input")
+            .takes_value(true)
+            .value_name("INPUT")
+            .multiple_occurrences(true)
+            .required_unless_present(FILES_FROM_ARG)
+            .allow_invalid_utf8(true)
+            .help(
+                "Files or directories to hash. Can be given more than once.\n\
+                 - means standard input.",
+            ),
+        Arg::new(FILES_FROM_ARG)
+            .long(FILES_FROM_ARG)
+            .takes_value(true)
+            .value_name("PATH")
+            .allow_invalid_utf8(true)
+            .help(
+                "Also hashes the files listed in PATH, one per line, or\n\
+                 read from standard input if PATH is -. Directories in\n\
+                 the list are skipped rather than walked.",
+            ),
+        Arg::new(NULL_ARG)
+            .short('0')
+            .long(NULL_ARG)
+            .requires(FILES_FROM_ARG)
+            .help(
+                "Entries in --files-from are terminated by NUL rather\n\
+                 than newline, as written by `find -print0` or\n\
+                 `git ls-files -z`.",
+            ),
+        Arg::new(SORT_ARG).long(SORT_ARG).help(
+            "Hashes files in sorted path order, so that the manifest\n\
+             comes out sorted. Entries are still written as they're\n\
+             hashed, except that a --files-from list is read in full\n\
+             first. With verify, the manifest must be sorted too, and\n\
+             the two are compared in constant memory.",
+        ),
+        progress_arg(),
+    ]
+}
+
+fn progress_arg() -> Arg<'static> {
+    Arg::new(PROGRESS_ARG).long(PROGRESS_ARG).help(
+        "Shows files and bytes hashed so far, throughput and ETA on\n\
+         standard error. On a terminal this is a status line;\n\
+         otherwise a log line is printed every 10 seconds.",
+    )
+}
+
+fn bag_arg() -> Arg<'static> {
+    Arg::new(BAG_ARG)
+        .allow_invalid_utf8(true)
+        .required(true)
+        .value_name("DIR")
+        .help("The bag's directory.")
+}
+
+// The arguments that say how files are hashed, for hash, verify and check.
+fn hashing_args() -> Vec<Arg<'static>> {
+    let mut args = vec![
+        algo_arg(),
+        Arg::new(KEYED_ARG).long(KEYED_ARG).help(
+            "Uses the keyed mode. The secret key is read from standard\n\
+             input, and it must be exactly 32 raw bytes.",
+        ),
+        Arg::new(DERIVE_KEY_ARG)
+            .long(DERIVE_KEY_ARG)
+            .conflicts_with(KEYED_ARG)
+            .takes_value(true)
+            .value_name("CONTEXT")
+            .help(
+                "Uses the key derivation mode, with the given\n\
+                 context string. Cannot be used with --keyed.",
+            ),
+    ];
+    args.extend(reading_args());
+    args
+}
+
+fn algo_arg() -> Arg<'static> {
+    Arg::new(ALGO_ARG)
+        .long(ALGO_ARG)
+        .takes_value(true)
+        .value_name("ALGO")
+        .possible_values(Algo::NAMES)
+        .multiple_values(true)
+        .use_value_delimiter(true)
+        .require_value_delimiter(true)
+        .help(
+            "The hash algorithm (default blake3). sha1 is only for\n\
+             legacy manifests, and xxh3 isn't cryptographic. A\n\
+             comma-separated list, e.g. blake3,sha256, computes them\n\
+             all in one read and records them joined with commas.",
+        )
+}
+
+// The arguments that control how files are read, shared by every subcommand
+// that hashes anything.
+fn reading_args() -> Vec<Arg<'static>> {
+    vec![
+        Arg::new(NUM_THREADS_ARG)
+            .long(NUM_THREADS_ARG)
+            .takes_value(true)
+            .value_name("NUM")
+            .help(
+                "The maximum number of threads to use. By\n\
+                 default, this is the number of logical cores.\n\
+                 If this flag is omitted, or if its value is 0,\n\
+                 RAYON_NUM_THREADS is also respected.",
+            ),
+        Arg::new(NO_MMAP_ARG).long(NO_MMAP_ARG).help(
+            "Disables memory mapping. Currently this also disables\n\
+             multithreading.",
+        ),
+        Arg::new(UNSTABLE_RETRIES_ARG)
+            .long(UNSTABLE_RETRIES_ARG)
+            .takes_value(true)
+            .value_name("NUM")
+            .help(
+                "How many times to rehash a file that changed while it\n\
+                 was being hashed, before recording it as UNSTABLE\n\
+                 (default 2).",
+            ),
+        Arg::new(READ_RETRIES_ARG)
+            .long(READ_RETRIES_ARG)
+            .takes_value(true)
+            .value_name("NUM")
+            .help(
+                "How many times to retry a file after a transient read\n\
+                 error (EIO, EAGAIN, ETIMEDOUT, ...), backing off\n\
+                 exponentially between attempts (default 0). Files\n\
+                 are read rather than mmapped when this is set, and\n\
+                 stdin (`-`) can't be retried.",
+            ),
+        Arg::new(TIMEOUT_ARG)
+            .long(TIMEOUT_ARG)
+            .takes_value(true)
+            .value_name("SECS")
+            .help(
+                "Gives up on a file that takes longer than this to\n\
+                 hash, recording it as ERROR(TimedOut). By default\n\
+                 there is no timeout.",
+            ),
+    ]
+}
+
+// Verify, check and diff all print the same kind of report.
+fn report_format_arg() -> Arg<'static> {
+    format_arg(
+        "Prints the report as text (the default), a JSON\n\
+         document, JSON Lines, or JUnit XML. Other than text,\n\
+         reports include every path, not just the ones that\n\
+         failed.",
+    )
+    .alias("report-format")
+    .possible_values(Format::REPORT_NAMES)
+}
+
+fn html_arg() -> Arg<'static> {
+    Arg::new(HTML_ARG)
+        .long(HTML_ARG)
+        .takes_value(true)
+        .value_name("PATH")
+        .allow_invalid_utf8(true)
+        .help(
+            "Also writes a self-contained HTML page to PATH, with a\n\
+             collapsible tree of what was added, removed or modified.\n\
+             Sizes and mtimes are shown where they're known, which\n\
+             for manifests means JSON ones.",
+        )
+}
+
+fn audit_arg(conflicts_with: &'static str) -> Arg<'static> {
+    Arg::new(AUDIT_ARG)
+        .long(AUDIT_ARG)
+        .conflicts_with(conflicts_with)
+        .help(
+            "Also matches files by hash, like hashdeep's audit mode,\n\
+             so that a file under a new path is reported as MOVED\n\
+             from a path that's gone, or COPIED from one that isn't,\n\
+             rather than as NEW.",
+        )
+}
+
+fn format_arg(help: &'static str) -> Arg<'static> {
+    Arg::new(FORMAT_ARG)
+        .long(FORMAT_ARG)
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(Format::NAMES)
+        .help(help)
+}
+
+// Verify has to hash with the same settings the manifest was made with, so it
+// shares these with hash.
+fn length_arg() -> Arg<'static> {
+    Arg::new(LENGTH_ARG)
+        .long(LENGTH_ARG)
+        .short('l')
+        .takes_value(true)
+        .value_name("LEN")
+        .help(
+            "The number of output bytes, prior to hex\n\
+             encoding (default 32)",
+        )
 }
 
 impl Args {
     fn parse() -> Result<Self> {
-        let inner = Command::new(NAME)
+        // wild::args_os() is equivalent to std::env::args_os() on Unix,
+        // but on Windows it adds support for globbing.
+        Self::parse_from(wild::args_os())
+    }
+
+    fn parse_from(argv: impl IntoIterator<Item = OsString>) -> Result<Self> {
+        let matches = Command::new(NAME)
             .version(env!("CARGO_PKG_VERSION"))
-            .arg(
-                Arg::new(FILE_ARG)
-                    .short('i')
-                    .long("input")
-                    .takes_value(true)
-                    .value_name("INPUT")
-                    .multiple_occurrences(true)
-                    .allow_invalid_utf8(true)
-                    .help(
-                        "Files to hash, or checkfiles to check. When no file is given,\n\
-                 or when - is given, read standard input.",
-                    ),
-            )
-            .arg_required_else_help(true)
-            .arg(
-                Arg::new(OUTPUT_ARG)
-                    .allow_invalid_utf8(true)
-                    .short('o')
-                    .long(OUTPUT_ARG)
-                    .takes_value(true)
-                    .value_name("OUTPUT")
-                    .help("Output file to write the hashmap to."),
-            )
+            .subcommand_required(true)
             .arg_required_else_help(true)
-            .arg(
-                Arg::new(LENGTH_ARG)
-                    .long(LENGTH_ARG)
-                    .short('l')
-                    .takes_value(true)
-                    .value_name("LEN")
-                    .help(
-                        "The number of output bytes, prior to hex\n\
-                         encoding (default 32)",
-                    ),
-            )
-            .arg(
-                Arg::new(NUM_THREADS_ARG)
-                    .long(NUM_THREADS_ARG)
-                    .takes_value(true)
-                    .value_name("NUM")
-                    .help(
-                        "The maximum number of threads to use. By\n\
-                         default, this is the number of logical cores.\n\
-                         If this flag is omitted, or if its value is 0,\n\
-                         RAYON_NUM_THREADS is also respected.",
+            .subcommand(
+                Command::new(HASH_CMD)
+                    .about("Hashes files and directories into a manifest")
+                    .args(input_args())
+                    .arg(
+                        Arg::new(OUTPUT_ARG)
+                            .allow_invalid_utf8(true)
+                            .short('o')
+                            .long(OUTPUT_ARG)
+                            .takes_value(true)
+                            .value_name("OUTPUT")
+                            .help(
+                                "Output file to write the manifest to. Entries are\n\
+                                 written as each file is hashed, to a temporary file\n\
+                                 that replaces OUTPUT once the run completes. When\n\
+                                 this is omitted, or when - is given, write to\n\
+                                 standard output.",
+                            ),
+                    )
+                    .arg(
+                        Arg::new(BACKUP_ARG)
+                            .long(BACKUP_ARG)
+                            .requires(OUTPUT_ARG)
+                            .help("Keeps the previous manifest as OUTPUT.bak."),
+                    )
+                    .arg(
+                        Arg::new(RESUME_ARG)
+                            .long(RESUME_ARG)
+                            .requires(OUTPUT_ARG)
+                            .help(
+                                "Picks up an interrupted run from OUTPUT.checkpoint.\n\
+                                 Files whose size, mtime, ctime and inode still match\n\
+                                 the checkpoint aren't hashed again. With --sort, the\n\
+                                 part of the tree the run got through isn't walked\n\
+                                 again either.",
+                            ),
+                    )
+                    .arg(
+                        Arg::new(CHECKPOINT_INTERVAL_ARG)
+                            .long(CHECKPOINT_INTERVAL_ARG)
+                            .requires(OUTPUT_ARG)
+                            .takes_value(true)
+                            .value_name("SECS")
+                            .help(
+                                "How often to sync progress to OUTPUT.checkpoint\n\
+                                 (default 30). The checkpoint is removed once the\n\
+                                 run completes.",
+                            ),
+                    )
+                    .arg(length_arg())
+                    .args(hashing_args())
+                    .arg(
+                        format_arg(
+                            "Writes the manifest as text `path:hash` lines (the\n\
+                             default), a JSON document, or JSON Lines. JSON\n\
+                             entries include each file's size, type and mtime.\n\
+                             gnu and bsd write checkfiles like sha256sum and\n\
+                             sha256sum --tag, and hashdeep writes hashdeep's CSV,\n\
+                             leaving out files that couldn't be hashed. mtree\n\
+                             writes an mtree(8) spec of a single directory, with\n\
+                             each entry's type, owner, mode and mtime, and SHA-256\n\
+                             digests unless --algo says otherwise.",
+                        )
+                        .conflicts_with(NO_NAMES_ARG),
+                    )
+                    .arg(
+                        Arg::new(NO_NAMES_ARG)
+                            .long(NO_NAMES_ARG)
+                            .help("Omits filenames in the output"),
+                    )
+                    .arg(
+                        Arg::new(RAW_ARG)
+                            .long(RAW_ARG)
+                            .conflicts_with(OUTPUT_ARG)
+                            .conflicts_with(NO_NAMES_ARG)
+                            .conflicts_with(FILES_FROM_ARG)
+                            .conflicts_with(FORMAT_ARG)
+                            .help(
+                                "Writes raw output bytes to stdout, rather than a\n\
+                                 manifest. In this case, only a single input file\n\
+                                 is allowed.",
+                            ),
                     ),
             )
-            .arg(Arg::new(KEYED_ARG).long(KEYED_ARG).requires(FILE_ARG).help(
-                "Uses the keyed mode. The secret key is read from standard\n\
-                         input, and it must be exactly 32 raw bytes.",
-            ))
-            .arg(
-                Arg::new(DERIVE_KEY_ARG)
-                    .long(DERIVE_KEY_ARG)
-                    .conflicts_with(KEYED_ARG)
-                    .takes_value(true)
-                    .value_name("CONTEXT")
-                    .help(
-                        "Uses the key derivation mode, with the given\n\
-                         context string. Cannot be used with --keyed.",
-                    ),
+            .subcommand(
+                Command::new(VERIFY_CMD)
+                    .about("Rehashes files and directories and compares them against a manifest")
+                    .args(input_args())
+                    .arg(
+                        Arg::new(MANIFEST_ARG)
+                            .allow_invalid_utf8(true)
+                            .short('m')
+                            .long(MANIFEST_ARG)
+                            .takes_value(true)
+                            .value_name("MANIFEST")
+                            .required(true)
+                            .help("The manifest to verify against."),
+                    )
+                    .arg(length_arg())
+                    .args(hashing_args())
+                    .arg(report_format_arg())
+                    .arg(html_arg())
+                    .arg(audit_arg(SORT_ARG)),
             )
-            .arg(Arg::new(NO_MMAP_ARG).long(NO_MMAP_ARG).help(
-                "Disables memory mapping. Currently this also disables\n\
-                 multithreading.",
-            ))
-            .arg(
-                Arg::new(NO_NAMES_ARG)
-                    .long(NO_NAMES_ARG)
-                    .help("Omits filenames in the output"),
+            .subcommand(
+                Command::new(CHECK_CMD)
+                    .about("Reads checksums from checkfiles, GNU or BSD style, and checks them")
+                    .arg(
+                        Arg::new(CHECKFILE_ARG)
+                            .allow_invalid_utf8(true)
+                            .multiple_values(true)
+                            .required(true)
+                            .value_name("CHECKFILE")
+                            .help("Checkfiles to check. - means standard input."),
+                    )
+                    .args(hashing_args())
+                    .arg(
+                        Arg::new(QUIET_ARG)
+                            .long(QUIET_ARG)
+                            .help("Skips printing OK for each successfully verified file."),
+                    )
+                    .arg(report_format_arg()),
             )
-            .arg(Arg::new(RAW_ARG).long(RAW_ARG).help(
-                "Writes raw output bytes to stdout, rather than hex.\n\
-                 --no-names is implied. In this case, only a single\n\
-                 input is allowed.",
-            ))
-            .arg(
-                Arg::new(CHECK_ARG)
-                    .long(CHECK_ARG)
-                    .short('c')
-                    .conflicts_with(DERIVE_KEY_ARG)
-                    .conflicts_with(KEYED_ARG)
-                    .conflicts_with(LENGTH_ARG)
-                    .conflicts_with(RAW_ARG)
-                    .conflicts_with(NO_NAMES_ARG)
-                    .help("Reads BLAKE3 sums from the [FILE]s and checks them"),
+            .subcommand(
+                Command::new(DIFF_CMD)
+                    .about("Compares two manifests without hashing anything")
+                    .arg(
+                        Arg::new(OLD_ARG)
+                            .allow_invalid_utf8(true)
+                            .required(true)
+                            .help("The manifest to compare against."),
+                    )
+                    .arg(
+                        Arg::new(NEW_ARG)
+                            .allow_invalid_utf8(true)
+                            .required(true)
+                            .help("The manifest to compare."),
+                    )
+                    .arg(Arg::new(SORTED_ARG).long(SORTED_ARG).help(
+                        "Both manifests are sorted, as written by `hash --sort`.\n\
+                         They're compared a line at a time, in constant memory.",
+                    ))
+                    .arg(report_format_arg())
+                    .arg(html_arg())
+                    .arg(audit_arg(SORTED_ARG)),
             )
-            .arg(
-                Arg::new(QUIET_ARG)
-                    .long(QUIET_ARG)
-                    .requires(CHECK_ARG)
-                    .help(
-                        "Skips printing OK for each successfully verified file.\n\
-                         Must be used with --check.",
+            .subcommand(
+                Command::new(BAG_CMD)
+                    .about("Creates and validates BagIt bags (RFC 8493)")
+                    .subcommand_required(true)
+                    .arg_required_else_help(true)
+                    .subcommand(
+                        Command::new(BAG_CREATE_CMD)
+                            .about("Turns a directory into a bag, in place")
+                            .arg(bag_arg().help(
+                                "The directory to turn into a bag. Its contents are\n\
+                                 moved into DIR/data.",
+                            ))
+                            .arg(
+                                Arg::new(INFO_ARG)
+                                    .long(INFO_ARG)
+                                    .takes_value(true)
+                                    .value_name("LABEL: VALUE")
+                                    .multiple_occurrences(true)
+                                    .help(
+                                        "Adds a line to bag-info.txt, e.g.\n\
+                                         --info 'Source-Organization: Example'.\n\
+                                         Can be given more than once.",
+                                    ),
+                            )
+                            .arg(algo_arg().help(
+                                "The hash algorithms to write manifests for\n\
+                                 (default sha512). A comma-separated list, e.g.\n\
+                                 sha256,sha512, writes a manifest for each.",
+                            ))
+                            .args(reading_args())
+                            .arg(progress_arg()),
+                    )
+                    .subcommand(
+                        Command::new(BAG_VALIDATE_CMD)
+                            .about("Checks that a bag is complete and its files match")
+                            .arg(bag_arg())
+                            .arg(Arg::new(FAST_ARG).long(FAST_ARG).help(
+                                "Only checks that the bag is complete: that every\n\
+                                 file is listed and present, and that Payload-Oxum\n\
+                                 adds up. Nothing is hashed.",
+                            ))
+                            .arg(algo_arg().help(
+                                "The manifests to check. By default, every\n\
+                                 manifest-<alg>.txt the bag has.",
+                            ))
+                            .args(reading_args())
+                            .arg(progress_arg())
+                            .arg(report_format_arg()),
                     ),
             )
-            .arg(
-                Arg::new(VERIFY_ARG)
-                    .help("Checks a hashmap against another hashmap. Outputs mismatches to 'modified.txt'.")
-                    .long(VERIFY_ARG)
+            .subcommand(
+                Command::new(GOMOD_CMD)
+                    .about("Computes Go's h1: hash of a module, as recorded in go.sum")
+                    .arg(
+                        Arg::new(MODULE_ARG)
+                            .allow_invalid_utf8(true)
+                            .required(true)
+                            .value_name("PATH")
+                            .help(
+                                "A module directory, a module zip, or a go.mod file\n\
+                                 for go.sum's /go.mod lines.",
+                            ),
+                    )
+                    .arg(
+                        Arg::new(PREFIX_ARG)
+                            .long(PREFIX_ARG)
+                            .takes_value(true)
+                            .value_name("MODULE@VERSION")
+                            .help(
+                                "The module and version, e.g.\n\
+                                 golang.org/x/mod@v0.1.0. Needed for directories,\n\
+                                 whose files are hashed under that name. Zips\n\
+                                 already name it.",
+                            ),
+                    )
+                    .arg(
+                        Arg::new(GO_SUM_ARG)
+                            .long(GO_SUM_ARG)
+                            .allow_invalid_utf8(true)
+                            .takes_value(true)
+                            .value_name("FILE")
+                            .help(
+                                "Checks the hash against the module's line in this\n\
+                                 go.sum, rather than printing it.",
+                            ),
+                    )
+                    .args(reading_args()),
             )
-            // wild::args_os() is equivalent to std::env::args_os() on Unix,
-            // but on Windows it adds support for globbing.
-            .get_matches_from(wild::args_os());
-        let file_args = vec![PathBuf::from(inner.value_of(FILE_ARG).unwrap())];
-        let output_path = inner.value_of(OUTPUT_ARG).unwrap().into();
-        let verify = inner.is_present(VERIFY_ARG);
-        if inner.is_present(RAW_ARG) && file_args.len() > 1 {
+            .subcommand(
+                Command::new(CARGO_CHECKSUM_CMD)
+                    .about("Checks a vendored crate against its .cargo-checksum.json")
+                    .arg(
+                        Arg::new(CRATE_ARG)
+                            .allow_invalid_utf8(true)
+                            .required(true)
+                            .value_name("DIR")
+                            .help("The vendored crate, e.g. vendor/serde."),
+                    )
+                    .arg(Arg::new(UPDATE_ARG).long(UPDATE_ARG).help(
+                        "Rewrites the file list in .cargo-checksum.json from\n\
+                         what's on disk, after patching the crate on purpose.\n\
+                         The package checksum is kept.",
+                    ))
+                    .args(reading_args())
+                    .arg(progress_arg())
+                    .arg(report_format_arg()),
+            )
+            .get_matches_from(argv);
+        let (mode, inner) = match matches.subcommand() {
+            Some((HASH_CMD, inner)) => (Mode::Hash, inner.clone()),
+            Some((VERIFY_CMD, inner)) => (Mode::Verify, inner.clone()),
+            Some((CHECK_CMD, inner)) => (Mode::Check, inner.clone()),
+            Some((DIFF_CMD, inner)) => (Mode::Diff, inner.clone()),
+            Some((BAG_CMD, bag)) => match bag.subcommand() {
+                Some((BAG_CREATE_CMD, inner)) => (Mode::BagCreate, inner.clone()),
+                Some((BAG_VALIDATE_CMD, inner)) => (Mode::BagValidate, inner.clone()),
+                _ => unreachable!("subcommand_required"),
+            },
+            Some((GOMOD_CMD, inner)) => (Mode::GoMod, inner.clone()),
+            Some((CARGO_CHECKSUM_CMD, inner)) => (Mode::CargoChecksum, inner.clone()),
+            _ => unreachable!("subcommand_required"),
+        };
+        let file_args: Vec<PathBuf> = match mode {
+            Mode::Hash | Mode::Verify => inner
+                .values_of_os(FILE_ARG)
+                .map(|values| values.map(PathBuf::from).collect())
+                .unwrap_or_default(),
+            Mode::Check => inner
+                .values_of_os(CHECKFILE_ARG)
+                .unwrap()
+                .map(PathBuf::from)
+                .collect(),
+            Mode::Diff => vec![
+                inner.value_of_os(OLD_ARG).unwrap().into(),
+                inner.value_of_os(NEW_ARG).unwrap().into(),
+            ],
+            // A bag's payload is everything under data/. create hashes it
+            // where it is, and only moves it there once that's worked.
+            Mode::BagCreate => vec![inner.value_of_os(BAG_ARG).unwrap().into()],
+            Mode::BagValidate => {
+                vec![Path::new(inner.value_of_os(BAG_ARG).unwrap()).join("data")]
+            }
+            Mode::GoMod => vec![inner.value_of_os(MODULE_ARG).unwrap().into()],
+            Mode::CargoChecksum => vec![inner.value_of_os(CRATE_ARG).unwrap().into()],
+        };
+        if mode == Mode::Hash && inner.is_present(RAW_ARG) && file_args.len() > 1 {
             bail!("Only one filename can be provided when using --raw");
         }
-        let base_hasher = if inner.is_present(KEYED_ARG) {
+        // Whatever we read from stdin is gone, so there's nothing to retry.
+        if file_args.iter().any(|path| path == Path::new("-"))
+            && inner.value_of(READ_RETRIES_ARG).is_some_and(|n| n != "0")
+        {
+            bail!("Can't retry reads from stdin (`-`) with --read-retries");
+        }
+        let mut algos = Vec::new();
+        if !matches!(mode, Mode::Diff | Mode::GoMod | Mode::CargoChecksum) {
+            for name in inner.values_of(ALGO_ARG).into_iter().flatten() {
+                let algo = Algo::from_name(name).unwrap();
+                ensure!(!algos.contains(&algo), "--algo {} is given twice", name);
+                algos.push(algo);
+            }
+        }
+        // RFC 8493 recommends SHA-512 for new bags, and validate checks
+        // whichever manifests the bag has.
+        if algos.is_empty() {
+            match mode {
+                Mode::BagCreate => algos.push(Algo::Sha512),
+                Mode::GoMod | Mode::CargoChecksum => algos.push(Algo::Sha256),
+                Mode::BagValidate => {
+                    algos = bag::manifest_algos(Path::new(inner.value_of_os(BAG_ARG).unwrap()))?
+                }
+                // mtree only has keywords for the SHA algorithms, and verify
+                // checks whichever digests the spec has.
+                Mode::Hash if inner.value_of(FORMAT_ARG) == Some("mtree") => {
+                    algos.push(Algo::Sha256)
+                }
+                Mode::Verify => {
+                    let manifest = Path::new(inner.value_of_os(MANIFEST_ARG).unwrap());
+                    match mtree::spec_algos(manifest)? {
+                        Some(found) if !found.is_empty() => algos = found,
+                        Some(_) => algos.push(Algo::Sha256),
+                        None => algos.push(Algo::Blake3),
+                    }
+                }
+                _ => algos.push(Algo::Blake3),
+            }
+        }
+        let algo = algos[0];
+        if algos.len() > 1 {
+            ensure!(mode != Mode::Check, "check takes a single --algo");
+            ensure!(
+                !(mode == Mode::Hash && inner.is_present(RAW_ARG)),
+                "--raw takes a single --algo"
+            );
+        }
+        let keyed = mode.takes_keys() && inner.is_present(KEYED_ARG);
+        let derive_key = if mode.takes_keys() {
+            inner.value_of(DERIVE_KEY_ARG)
+        } else {
+            None
+        };
+        let base_hasher: Box<dyn Digest> = if mode == Mode::Diff {
+            algo.hasher()
+        } else if algos.len() > 1 {
+            ensure!(
+                !keyed && derive_key.is_none(),
+                "--keyed and --derive-key take a single --algo"
+            );
+            Box::new(Several::new(&algos))
+        } else if keyed {
+            ensure!(algo == Algo::Blake3, "--keyed only works with BLAKE3");
             // In keyed mode, since stdin is used for the key, we can't handle
             // `-` arguments. Input::open handles that case below.
-            blake3::Hasher::new_keyed(&read_key_from_stdin()?)
-        } else if let Some(context) = inner.value_of(DERIVE_KEY_ARG) {
-            blake3::Hasher::new_derive_key(context)
+            Box::new(blake3::Hasher::new_keyed(&read_key_from_stdin()?))
+        } else if let Some(context) = derive_key {
+            ensure!(algo == Algo::Blake3, "--derive-key only works with BLAKE3");
+            Box::new(blake3::Hasher::new_derive_key(context))
         } else {
-            blake3::Hasher::new()
+            algo.hasher()
         };
         Ok(Self {
             inner,
+            mode,
             file_args,
-            output_path,
+            algos,
             base_hasher,
-            verify,
+            sparse_stats: SparseStats::default(),
         })
     }
 
@@ -177,8 +763,111 @@ impl Args {
         }
     }
 
-    fn check(&self) -> bool {
-        self.inner.is_present(CHECK_ARG)
+    fn output_path(&self) -> Option<&Path> {
+        self.inner.value_of_os(OUTPUT_ARG).map(Path::new)
+    }
+
+    fn backup(&self) -> bool {
+        self.inner.is_present(BACKUP_ARG)
+    }
+
+    fn resume(&self) -> bool {
+        self.inner.is_present(RESUME_ARG)
+    }
+
+    fn checkpoint_interval(&self) -> Result<Duration> {
+        if let Some(secs) = self.inner.value_of(CHECKPOINT_INTERVAL_ARG) {
+            let secs = secs
+                .parse::<f64>()
+                .context("Failed to parse checkpoint interval.")?;
+            ensure!(
+                secs >= 0.0 && secs.is_finite(),
+                "Checkpoint interval must not be negative."
+            );
+            Ok(Duration::from_secs_f64(secs))
+        } else {
+            Ok(Duration::from_secs(30))
+        }
+    }
+
+    fn is_bag(&self) -> bool {
+        matches!(self.mode, Mode::BagCreate | Mode::BagValidate)
+    }
+
+    fn bag_path(&self) -> &Path {
+        Path::new(self.inner.value_of_os(BAG_ARG).unwrap())
+    }
+
+    fn bag_info(&self) -> Vec<&str> {
+        self.inner
+            .values_of(INFO_ARG)
+            .map(Iterator::collect)
+            .unwrap_or_default()
+    }
+
+    fn fast(&self) -> bool {
+        self.inner.is_present(FAST_ARG)
+    }
+
+    fn gomod_path(&self) -> &Path {
+        &self.file_args[0]
+    }
+
+    fn gomod_prefix(&self) -> Option<&str> {
+        self.inner.value_of(PREFIX_ARG)
+    }
+
+    fn go_sum_path(&self) -> Option<&Path> {
+        self.inner.value_of_os(GO_SUM_ARG).map(Path::new)
+    }
+
+    fn crate_path(&self) -> &Path {
+        &self.file_args[0]
+    }
+
+    fn update(&self) -> bool {
+        self.inner.is_present(UPDATE_ARG)
+    }
+
+    // Bags and vendored crates don't take --files-from, and their manifests
+    // are always sorted.
+    fn files_from(&self) -> Option<&Path> {
+        if self.is_bag() || self.mode == Mode::CargoChecksum {
+            return None;
+        }
+        self.inner.value_of_os(FILES_FROM_ARG).map(Path::new)
+    }
+
+    fn null(&self) -> bool {
+        self.inner.is_present(NULL_ARG)
+    }
+
+    fn sort(&self) -> bool {
+        self.is_bag() || self.mode == Mode::CargoChecksum || self.inner.is_present(SORT_ARG)
+    }
+
+    fn html_path(&self) -> Option<&Path> {
+        self.inner.value_of_os(HTML_ARG).map(Path::new)
+    }
+
+    fn format(&self) -> Format {
+        Format::from_name(self.inner.value_of(FORMAT_ARG))
+    }
+
+    fn progress(&self) -> bool {
+        self.inner.is_present(PROGRESS_ARG)
+    }
+
+    fn audit(&self) -> bool {
+        self.inner.is_present(AUDIT_ARG)
+    }
+
+    fn sorted(&self) -> bool {
+        self.inner.is_present(SORTED_ARG)
+    }
+
+    fn manifest_path(&self) -> &Path {
+        Path::new(self.inner.value_of_os(MANIFEST_ARG).unwrap())
     }
 
     fn raw(&self) -> bool {
@@ -193,16 +882,67 @@ impl Args {
         self.inner.is_present(NO_NAMES_ARG)
     }
 
+    // The first algorithm is the only one for everything but hash and verify.
+    fn algo(&self) -> Algo {
+        self.algos[0]
+    }
+
+    // Only BLAKE3 has a variable output length. For the subcommands that don't
+    // take --length, this is always the algorithm's default. With several
+    // algorithms, each digest has its default length, and this isn't used.
     fn len(&self) -> Result<u64> {
-        if let Some(length) = self.inner.value_of(LENGTH_ARG) {
-            length.parse::<u64>().context("Failed to parse length.")
+        let length = match self.mode {
+            Mode::Hash | Mode::Verify => self.inner.value_of(LENGTH_ARG),
+            _ => None,
+        };
+        match length {
+            Some(length) => {
+                ensure!(
+                    self.algos == [Algo::Blake3],
+                    "--length only works with BLAKE3 on its own"
+                );
+                length.parse::<u64>().context("Failed to parse length.")
+            }
+            None => Ok(self.algo().output_len() as u64),
+        }
+    }
+
+    fn unstable_retries(&self) -> Result<u32> {
+        if let Some(retries) = self.inner.value_of(UNSTABLE_RETRIES_ARG) {
+            retries
+                .parse::<u32>()
+                .context("Failed to parse unstable retries.")
         } else {
-            Ok(blake3::OUT_LEN as u64)
+            Ok(2)
+        }
+    }
+
+    fn read_retries(&self) -> Result<u32> {
+        if let Some(retries) = self.inner.value_of(READ_RETRIES_ARG) {
+            retries
+                .parse::<u32>()
+                .context("Failed to parse read retries.")
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn timeout(&self) -> Result<Option<Duration>> {
+        if let Some(secs) = self.inner.value_of(TIMEOUT_ARG) {
+            let secs = secs.parse::<f64>().context("Failed to parse timeout.")?;
+            ensure!(secs > 0.0 && secs.is_finite(), "Timeout must be positive.");
+            Ok(Some(Duration::from_secs_f64(secs)))
+        } else {
+            Ok(None)
         }
     }
 
     fn keyed(&self) -> bool {
-        self.inner.is_present(KEYED_ARG)
+        self.mode.takes_keys() && self.inner.is_present(KEYED_ARG)
+    }
+
+    fn derive_key(&self) -> bool {
+        self.mode.takes_keys() && self.inner.is_present(DERIVE_KEY_ARG)
     }
 
     fn quiet(&self) -> bool {
@@ -210,8 +950,40 @@ impl Args {
     }
 }
 
+// Totals for the sparse files we've hashed, reported to stderr at the end of
+// a run. Atomic so that they can be bumped through the shared &Args.
+#[derive(Default)]
+struct SparseStats {
+    files: AtomicU64,
+    apparent_bytes: AtomicU64,
+    hole_bytes: AtomicU64,
+}
+
+impl SparseStats {
+    fn record(&self, apparent_bytes: u64, hole_bytes: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.apparent_bytes
+            .fetch_add(apparent_bytes, Ordering::Relaxed);
+        self.hole_bytes.fetch_add(hole_bytes, Ordering::Relaxed);
+    }
+
+    fn report(&self) {
+        let files = self.files.load(Ordering::Relaxed);
+        if files > 0 {
+            eprintln!(
+                "{}: {} sparse file(s), {} of {} bytes were holes and not read",
+                NAME,
+                files,
+                self.hole_bytes.load(Ordering::Relaxed),
+                self.apparent_bytes.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
 enum Input {
     Mmap(io::Cursor<memmap::Mmap>),
+    Sparse(File),
     File(File),
     Stdin,
 }
@@ -221,6 +993,13 @@ impl Input {
     // that this convention applies both to command line arguments, and to
     // filepaths that appear in a checkfile.
     fn open(path: &Path, args: &Args) -> Result<Self> {
+        Self::open_with(path, args, !args.no_mmap())
+    }
+
+    // Like open(), but never mmaps when `mmap` is false. A read error on a
+    // mapped file is a SIGBUS rather than an io::Error, so anything that
+    // wants to catch read errors and retry has to read the file instead.
+    fn open_with(path: &Path, args: &Args, mmap: bool) -> Result<Self> {
         if path == Path::new("-") {
             if args.keyed() {
                 bail!("Cannot open `-` in keyed mode");
@@ -228,7 +1007,10 @@ impl Input {
             return Ok(Self::Stdin);
         }
         let file = File::open(path)?;
-        if !args.no_mmap() {
+        if is_sparse(&file)? {
+            return Ok(Self::Sparse(file));
+        }
+        if mmap {
             if let Some(mmap) = maybe_memmap_file(&file)? {
                 return Ok(Self::Mmap(io::Cursor::new(mmap)));
             }
@@ -236,14 +1018,26 @@ impl Input {
         Ok(Self::File(file))
     }
 
-    fn hash(&mut self, args: &Args) -> Result<blake3::OutputReader> {
-        let mut hasher = args.base_hasher.clone();
+    fn hash(&mut self, args: &Args) -> Result<HashOutput> {
+        self.hash_with(args, args.base_hasher.clone())
+    }
+
+    fn hash_with(&mut self, args: &Args, mut hasher: Box<dyn Digest>) -> Result<HashOutput> {
         match self {
             // The fast path: If we mmapped the file successfully, hash using
             // multiple threads. This doesn't work on stdin, or on some files,
             // and it can also be disabled with --no-mmap.
             Self::Mmap(cursor) => {
-                hasher.update_rayon(cursor.get_ref());
+                hasher.update_parallel(cursor.get_ref());
+            }
+            // Sparse files are hashed a data region at a time, with the holes
+            // in between fed to the hasher from a static buffer of zeros. The
+            // result is identical to a dense read, but we never page in or
+            // read the holes from disk.
+            Self::Sparse(file) => {
+                let apparent_bytes = file.metadata()?.len();
+                let hole_bytes = copy_sparse(file, apparent_bytes, &mut *hasher)?;
+                args.sparse_stats.record(apparent_bytes, hole_bytes);
             }
             // The slower paths, for stdin or files we didn't/couldn't mmap.
             // This is currently all single-threaded. Doing multi-threaded
@@ -255,15 +1049,15 @@ impl Input {
             // one. We might implement that in the future, but since this is
             // the slow path anyway, it's not high priority.
             Self::File(file) => {
-                copy_wide(file, &mut hasher)?;
+                copy_wide(file, &mut *hasher)?;
             }
             Self::Stdin => {
                 let stdin = io::stdin();
                 let lock = stdin.lock();
-                copy_wide(lock, &mut hasher)?;
+                copy_wide(lock, &mut *hasher)?;
             }
         }
-        Ok(hasher.finalize_xof())
+        Ok(hasher.finalize())
     }
 }
 
@@ -271,7 +1065,7 @@ impl Read for Input {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             Self::Mmap(cursor) => cursor.read(buf),
-            Self::File(file) => file.read(buf),
+            Self::Sparse(file) | Self::File(file) => file.read(buf),
             Self::Stdin => io::stdin().read(buf),
         }
     }
@@ -281,7 +1075,7 @@ impl Read for Input {
 // that we support, but `std::io::copy` currently uses 8 KiB. Most platforms
 // can support at least 64 KiB, and there's some performance benefit to using
 // bigger reads, so that's what we use here.
-fn copy_wide(mut reader: impl Read, hasher: &mut blake3::Hasher) -> io::Result<u64> {
+fn copy_wide(mut reader: impl Read, hasher: &mut dyn Digest) -> io::Result<u64> {
     let mut buffer = [0; 65536];
     let mut total = 0;
     loop {
@@ -305,7 +1099,7 @@ fn maybe_memmap_file(file: &File) -> Result<Option<memmap::Mmap>> {
     let file_size = metadata.len();
     Ok(
         if !metadata.is_file() // Not a real file.
-            || file_size > isize::max_value() as u64 // Too long to safely map. https://github.com/danburkert/memmap-rs/issues/69
+            || file_size > isize::MAX as u64 // Too long to safely map. https://github.com/danburkert/memmap-rs/issues/69
             || file_size == 0 // Mapping an empty file currently fails. https://github.com/danburkert/memmap-rs/issues/72
             || file_size < 16 * 1024 // Mapping small files is not worth it.
         {
@@ -323,26 +1117,96 @@ fn maybe_memmap_file(file: &File) -> Result<Option<memmap::Mmap>> {
     )
 }
 
-fn write_hex_output(mut output: blake3::OutputReader, args: &Args) -> String {
-    // Encoding multiples of the block size is most efficient.
-    let mut len = args.len().unwrap();
-    let mut block = [0; blake3::guts::BLOCK_LEN];
-    let mut out = String::new();
+// A file is worth treating as sparse if fewer blocks are allocated for it than
+// its length would need. Filesystems that don't report holes (or platforms
+// without SEEK_DATA/SEEK_HOLE) always take the dense path.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn is_sparse(file: &File) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = file.metadata()?;
+    Ok(metadata.is_file() && metadata.blocks().saturating_mul(512) < metadata.len())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn is_sparse(_file: &File) -> Result<bool> {
+    Ok(false)
+}
+
+// Returns the offset of the next data region (SEEK_DATA) or hole (SEEK_HOLE)
+// at or after `offset`, or None if there is no more data past `offset`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_sparse(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+    let offset = offset
+        .try_into()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    match unsafe { libc::lseek(file.as_raw_fd(), offset, whence) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        }
+        n => Ok(Some(n as u64)),
+    }
+}
+
+// Feeds `len` zero bytes to the hasher without touching the disk. BLAKE3 mixes
+// the chunk counter into every leaf, and the other algorithms are sequential,
+// so there's no shortcut for a run of zeros: holes still have to be hashed,
+// but this is bounded by CPU rather than I/O.
+fn hash_zeros(hasher: &mut dyn Digest, mut len: u64) {
+    static ZEROS: [u8; 1 << 20] = [0; 1 << 20];
     while len > 0 {
-        output.fill(&mut block);
-        let hex_str = hex::encode(&block[..]);
-        let take_bytes = cmp::min(len, block.len() as u64);
-        out.push_str(&hex_str[..2 * take_bytes as usize]);
-        len -= take_bytes;
+        let n = cmp::min(len, ZEROS.len() as u64) as usize;
+        hasher.update_parallel(&ZEROS[..n]);
+        len -= n as u64;
+    }
+}
+
+// Hashes the first `len` bytes of a sparse file, reading only the regions
+// that SEEK_DATA reports as allocated. Returns the number of bytes that were
+// holes.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn copy_sparse(file: &mut File, len: u64, hasher: &mut dyn Digest) -> io::Result<u64> {
+    let mut pos = 0;
+    let mut hole_bytes = 0;
+    while pos < len {
+        let data = seek_sparse(file, pos, libc::SEEK_DATA)?.map_or(len, |n| cmp::min(n, len));
+        hash_zeros(hasher, data - pos);
+        hole_bytes += data - pos;
+        if data == len {
+            break;
+        }
+        let hole = seek_sparse(file, data, libc::SEEK_HOLE)?.map_or(len, |n| cmp::min(n, len));
+        file.seek(io::SeekFrom::Start(data))?;
+        let n = copy_wide((&*file).take(hole - data), hasher)?;
+        if n < hole - data {
+            // The file was truncated underneath us. Stop where a dense read
+            // would have stopped.
+            break;
+        }
+        pos = hole;
     }
-    out
+    Ok(hole_bytes)
 }
 
-fn write_raw_output(output: blake3::OutputReader, args: &Args) -> Result<()> {
-    let mut output = output.take(args.len()?);
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn copy_sparse(file: &mut File, len: u64, hasher: &mut dyn Digest) -> io::Result<u64> {
+    copy_wide((&*file).take(len), hasher)?;
+    Ok(0)
+}
+
+fn write_hex_output(output: HashOutput, args: &Args) -> String {
+    output.hex(args.len().unwrap() as usize)
+}
+
+fn write_raw_output(output: HashOutput, args: &Args) -> Result<()> {
     let stdout = std::io::stdout();
-    let mut handler = stdout.lock();
-    std::io::copy(&mut output, &mut handler)?;
+    let handler = stdout.lock();
+    output.write_raw(args.len()?, handler)?;
     Ok(())
 }
 
@@ -362,13 +1226,9 @@ fn read_key_from_stdin() -> Result<[u8; blake3::KEY_LEN]> {
     }
 }
 
-/*struct FilepathString {
-    //filepath_string: String,
-    is_escaped: bool,
-}*/
-
-// returns (string, did_escape)
-fn filepath_to_string(filepath: &Path) -> bool {
+// Returns the path as it's written in manifests. Backslashes and newlines are
+// escaped, so the result contains a backslash if and only if it was escaped.
+fn filepath_to_string(filepath: &Path) -> String {
     let unicode_cow = filepath.to_string_lossy();
     let mut filepath_string = unicode_cow.to_string();
     // If we're on Windows, normalize backslashes to forward slashes. This
@@ -380,23 +1240,14 @@ fn filepath_to_string(filepath: &Path) -> bool {
     if cfg!(windows) {
         filepath_string = filepath_string.replace('\\', "/");
     }
-    let mut is_escaped = false;
-    if filepath_string.contains('\\') || filepath_string.contains('\n') {
-        is_escaped = true;
-    }
-    is_escaped
+    escape_filepath(filepath_string)
 }
 
-fn hex_half_byte(c: char) -> Result<u8> {
-    // The hex characters in the hash must be lowercase for now, though we
-    // could support uppercase too if we wanted to.
-    if ('0'..='9').contains(&c) {
-        return Ok(c as u8 - 48);
-    }
-    if ('a'..='f').contains(&c) {
-        return Ok(c as u8 - 107);
+fn escape_filepath(mut filepath_string: String) -> String {
+    if filepath_string.contains('\\') || filepath_string.contains('\n') {
+        filepath_string = filepath_string.replace('\\', "\\\\").replace('\n', "\\n");
     }
-    bail!("Invalid hex");
+    filepath_string
 }
 
 // The `check` command is a security tool. That means it's much better for a
@@ -436,6 +1287,8 @@ fn unescape(mut path: &str) -> Result<String> {
         match path[i + 1..].chars().next().unwrap() {
             // Anything other than a recognized escape sequence is an error.
             'n' => unescaped.push('\n'),
+            // Newer coreutils also escape carriage returns.
+            'r' => unescaped.push('\r'),
             '\\' => unescaped.push('\\'),
             _ => bail!("Invalid backslash escape"),
         }
@@ -450,10 +1303,16 @@ struct ParsedCheckLine {
     file_string: String,
     is_escaped: bool,
     file_path: PathBuf,
-    expected_hash: blake3::Hash,
+    // Set for BSD-style `ALGO (path) = hash` lines, which name their
+    // algorithm. GNU-style lines use --algo.
+    algo: Option<Algo>,
+    expected_hash: Vec<u8>,
 }
 
-fn parse_check_line(mut line: &str) -> Result<ParsedCheckLine> {
+// Reads both the GNU layout, `hash  path` (or `hash *path`, for files hashed
+// in binary mode, which makes no difference to us), and the BSD `--tag`
+// layout, `SHA256 (path) = hash`.
+fn parse_check_line(mut line: &str, algo: Algo) -> Result<ParsedCheckLine> {
     // Trim off the trailing newline, if any.
     line = line.trim_end_matches('\n');
     // If there's a backslash at the front of the line, that means we need to
@@ -468,27 +1327,31 @@ fn parse_check_line(mut line: &str) -> Result<ParsedCheckLine> {
         is_escaped = true;
         line = &line[1..];
     }
-    // The front of the line must be a hash of the usual length, followed by
-    // two spaces. The hex characters in the hash must be lowercase for now,
-    // though we could support uppercase too if we wanted to.
-    let hash_hex_len = 2 * blake3::OUT_LEN;
-    let prefix_len = hash_hex_len + 2;
-    ensure!(line.len() > prefix_len, "Short line");
+    let (algo, hash_hex, file_string) = match parse_tag_line(line)? {
+        Some((tag_algo, file_string, hash_hex)) => (Some(tag_algo), hash_hex, file_string),
+        None => {
+            // The front of the line must be a hash of the algorithm's length,
+            // followed by two spaces or a space and an asterisk.
+            let hash_hex_len = 2 * algo.output_len();
+            let prefix_len = hash_hex_len + 2;
+            ensure!(line.len() > prefix_len, "Short line");
+            ensure!(
+                line.chars().take(prefix_len).all(|c| c.is_ascii()),
+                "Non-ASCII prefix"
+            );
+            let separator = &line[hash_hex_len..][..2];
+            ensure!(separator == "  " || separator == " *", "Invalid space");
+            (None, &line[..hash_hex_len], &line[prefix_len..])
+        }
+    };
+    // The length was checked in bytes, so anything non-ASCII has to be
+    // ruled out before decoding.
     ensure!(
-        line.chars().take(prefix_len).all(|c| c.is_ascii()),
-        "Non-ASCII prefix"
+        hash_hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        "Invalid hex"
     );
-    ensure!(&line[hash_hex_len..][..2] == "  ", "Invalid space");
-    // Decode the hash hex.
-    let mut hash_bytes = [0; blake3::OUT_LEN];
-    let mut hex_chars = line[..hash_hex_len].chars();
-    for byte in &mut hash_bytes {
-        let high_char = hex_chars.next().unwrap();
-        let low_char = hex_chars.next().unwrap();
-        *byte = 16 * hex_half_byte(high_char)? + hex_half_byte(low_char)?;
-    }
-    let expected_hash: blake3::Hash = hash_bytes.into();
-    let file_string = line[prefix_len..].to_string();
+    let expected_hash = hex::decode(hash_hex).context("Invalid hex")?;
+    let file_string = file_string.to_string();
     let file_path_string = if is_escaped {
         // If we detected a backslash at the start of the line earlier, now we
         // need to unescape backslashes and newlines.
@@ -501,77 +1364,501 @@ fn parse_check_line(mut line: &str) -> Result<ParsedCheckLine> {
         file_string,
         is_escaped,
         file_path: file_path_string.into(),
+        algo,
         expected_hash,
     })
 }
 
-fn hash_one_input(path: &Path, args: &Args) -> String {
-    let mut input = Input::open(path, args).unwrap();
-    let output = input.hash(args).unwrap();
-    if args.raw() {
-        write_raw_output(output.clone(), args).expect("Could not write raw output");
-        //return Ok(());
+// Splits `SHA256 (path) = hash` into its algorithm, path and hash, or returns
+// None if the line doesn't start with a known tag. Paths may contain `) = `,
+// but hashes never do, so we split on the last one.
+fn parse_tag_line(line: &str) -> Result<Option<(Algo, &str, &str)>> {
+    let algo = match line.split_once(" (") {
+        Some((tag, _)) => match Algo::from_tag(tag) {
+            Some(algo) => algo,
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+    let rest = &line[algo.tag().len() + 2..];
+    let (file_string, hash_hex) = rest.rsplit_once(") = ").context("Invalid tag line")?;
+    // BLAKE3 lines may have been written with --length.
+    let length_ok = if algo == Algo::Blake3 {
+        !hash_hex.is_empty() && hash_hex.len() % 2 == 0
+    } else {
+        hash_hex.len() == 2 * algo.output_len()
+    };
+    ensure!(length_ok, "Wrong hash length for {}", algo.tag());
+    ensure!(
+        hash_hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        "Invalid hex in {} line",
+        algo.tag()
+    );
+    ensure!(!file_string.is_empty(), "Empty path");
+    Ok(Some((algo, file_string, hash_hex)))
+}
+
+// The parts of a file's metadata that change whenever its contents do. We take
+// one of these before and after hashing, and if they differ the hash may be of
+// a torn state that never existed on disk.
+#[derive(Clone, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    changed: (i64, i64),
+    inode: (u64, u64),
+}
+
+impl FileStamp {
+    // Stdin has nothing to stat, so it always compares equal to itself.
+    fn of(path: &Path) -> Result<Option<Self>> {
+        if path == Path::new("-") {
+            return Ok(None);
+        }
+        let metadata = fs::metadata(path)?;
+        #[cfg(unix)]
+        let (changed, inode) = {
+            use std::os::unix::fs::MetadataExt;
+            (
+                (metadata.ctime(), metadata.ctime_nsec()),
+                (metadata.dev(), metadata.ino()),
+            )
+        };
+        #[cfg(not(unix))]
+        let (changed, inode) = ((0, 0), (0, 0));
+        Ok(Some(Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            changed,
+            inode,
+        }))
+    }
+}
+
+// What gets recorded against a path in the manifest.
+enum Status {
+    Hashed(String),
+    // The file kept changing while we hashed it, so no hash was recorded.
+    Unstable,
+    // The file couldn't be read. This holds the io::ErrorKind name, e.g.
+    // "PermissionDenied", or "Other" for errors that didn't come from I/O.
+    Error(String),
+}
+
+impl Status {
+    fn is_hashed(&self) -> bool {
+        matches!(self, Self::Hashed(_))
+    }
+
+    // The label this status is counted under in the end-of-run summary.
+    fn summary_label(&self) -> &str {
+        match self {
+            Self::Hashed(_) => "OK",
+            Self::Unstable => "UNSTABLE",
+            Self::Error(kind) => kind,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hashed(hash) => f.write_str(hash),
+            Self::Unstable => f.write_str("UNSTABLE"),
+            Self::Error(kind) => write!(f, "ERROR({})", kind),
+        }
+    }
+}
+
+// Manifest values that aren't a hash can never match anything, so verify
+// reports them as they are rather than as a MISMATCH.
+fn is_hash_value(value: &str) -> bool {
+    value != "UNSTABLE" && !value.starts_with("ERROR")
+}
+
+fn error_kind(e: &anyhow::Error) -> String {
+    e.chain()
+        .find_map(|cause| {
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                Some(e.kind())
+            } else {
+                cause
+                    .downcast_ref::<walkdir::Error>()
+                    .and_then(|e| e.io_error())
+                    .map(|e| e.kind())
+            }
+        })
+        .map_or_else(|| "Other".to_string(), |kind| format!("{:?}", kind))
+}
+
+// Counts of every file that didn't end up with a hash, by status, so that a
+// long run ends with something more useful than scrolling back through stderr.
+#[derive(Default)]
+struct ErrorSummary {
+    total: u64,
+    by_label: BTreeMap<String, u64>,
+}
+
+impl ErrorSummary {
+    fn record(&mut self, status: &Status) {
+        self.total += 1;
+        if !status.is_hashed() {
+            *self
+                .by_label
+                .entry(status.summary_label().to_string())
+                .or_default() += 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_label.is_empty()
+    }
+
+    fn report(&self) {
+        if self.is_empty() {
+            return;
+        }
+        let failed: u64 = self.by_label.values().sum();
+        eprintln!(
+            "{}: {} of {} entries could not be hashed:",
+            NAME, failed, self.total
+        );
+        for (label, count) in &self.by_label {
+            eprintln!("{}:   {}: {}", NAME, label, count);
+        }
+    }
+}
+
+// A manifest entry: the final status of a file, and how many extra attempts
+// it took to get there. The retry count is appended to the manifest line as
+// ` retries=N` when it's nonzero.
+struct Entry {
+    status: Status,
+    retries: u32,
+    // The file's stat from around a successful hash, if it held still for the
+    // whole time. Checkpoints need it to tell whether the hash is still good.
+    stamp: Option<FileStamp>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if self.retries > 0 {
+            write!(f, " retries={}", self.retries)?;
+        }
+        Ok(())
+    }
+}
+
+// Manifest lines are `path:value`, with paths escaped the same way as in
+// checkfiles: if the path contains a backslash or a newline, they're escaped
+// and the whole line starts with a backslash. Manifest keys are the escaped
+// form, which is just as unique as the path itself.
+fn write_manifest_line(mut w: impl Write, path: &str, entry: &Entry) -> io::Result<()> {
+    let prefix = if path.contains('\\') { "\\" } else { "" };
+    writeln!(w, "{}{}:{}", prefix, path, entry)
+}
+
+// Writes a checkfile line in the layout of the coreutils tools, escaped the
+// same way as a manifest line, or one BSD line per algorithm with several.
+// There's no way to record a file that couldn't be hashed, so those are left
+// out, as sha256sum leaves them out, with the error on stderr.
+fn write_checksum_lines(
+    mut w: impl Write,
+    format: Format,
+    algos: &[Algo],
+    path: &str,
+    entry: &Entry,
+) -> io::Result<()> {
+    let hashes = match &entry.status {
+        Status::Hashed(hashes) => hashes,
+        _ => return Ok(()),
+    };
+    let prefix = if path.contains('\\') { "\\" } else { "" };
+    if format == Format::Gnu {
+        return writeln!(w, "{}{}  {}", prefix, hashes, path);
+    }
+    for (algo, hash) in algos.iter().zip(hashes.split(',')) {
+        writeln!(w, "{}{} ({}) = {}", prefix, algo.tag(), path, hash)?;
+    }
+    Ok(())
+}
+
+// Splits a manifest line into its (still escaped) path and value, dropping
+// any annotations (like ` retries=N`) after the value. Paths may contain
+// colons, but values never do, so we split on the last one.
+// The last line of a manifest cut short by an interrupt, in the formats that
+// aren't JSON, so that one piped from stdout says so too. It has no `:`, so
+// it can't be a text manifest entry, and checkfile and hashdeep readers skip
+// `#` lines anyway.
+const PARTIAL_MARKER: &str = "# partial, interrupted before the end";
+
+fn parse_manifest_line(line: &str) -> Result<(&str, &str)> {
+    let line = line.strip_prefix('\\').unwrap_or(line);
+    let (path, rest) = line
+        .rsplit_once(':')
+        .context("Missing ':' in manifest line")?;
+    let value = rest.split(' ').next().unwrap();
+    Ok((path, value))
+}
+
+// Reads a text manifest, or a JSON or hashdeep one written with --format.
+// hashdeep manifests can have several hash columns, and `algos` picks which.
+fn read_manifest(path: &Path, algos: Option<&[Algo]>) -> Result<HashMap<String, String>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    let first_line = first_line.trim_end_matches('\n');
+    if first_line == hashdeep::HEADER {
+        return hashdeep::read(reader, algos)
+            .with_context(|| format!("Failed to parse {}", path.display()));
+    }
+    let format = format::detect(first_line);
+    if format != Format::Text {
+        return format::read_json_manifest(first_line, reader, format)
+            .with_context(|| format!("Failed to parse {}", path.display()));
+    }
+    let mut manifest = HashMap::new();
+    let first_line = (!first_line.is_empty()).then(|| Ok(first_line.to_string()));
+    for line in first_line.into_iter().chain(reader.lines()) {
+        let line = line?;
+        if line == PARTIAL_MARKER {
+            continue;
+        }
+        let (path, value) = parse_manifest_line(&line)?;
+        manifest.insert(path.to_string(), value.to_string());
+    }
+    Ok(manifest)
+}
+
+// Errors that might go away if we try again, as opposed to e.g. ENOENT or
+// EACCES which certainly won't. Our own --timeout isn't one of these, since
+// retrying a hung file would just hang again.
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        let e = match cause.downcast_ref::<io::Error>() {
+            Some(e) => e,
+            None => return false,
+        };
+        #[cfg(unix)]
+        let transient = matches!(
+            e.raw_os_error(),
+            Some(libc::EIO | libc::EAGAIN | libc::EBUSY | libc::ETIMEDOUT | libc::ESTALE)
+        );
+        #[cfg(not(unix))]
+        let transient = e.raw_os_error().is_some()
+            && matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+            );
+        transient
+    })
+}
+
+// 100ms, 200ms, 400ms, ... capped at 10s.
+fn backoff(attempt: u32) -> Duration {
+    let millis = 100u64 << cmp::min(attempt, 7);
+    cmp::min(Duration::from_millis(millis), Duration::from_secs(10))
+}
+
+// One attempt at hashing a file. Returns None if its stat changed while we
+// were hashing it. Files are only mmapped on a first attempt with
+// --read-retries off, since a read error on a mapped file kills the process
+// instead of coming back as an error we could retry.
+fn hash_attempt(path: &Path, args: &Args, attempt: u32) -> Result<Option<HashOutput>> {
+    let before = FileStamp::of(path)?;
+    let mmap = !args.no_mmap() && attempt == 0 && args.read_retries()? == 0;
+    let mut input = Input::open_with(path, args, mmap)?;
+    let output = input.hash(args)?;
+    Ok(if FileStamp::of(path)? == before {
+        Some(output)
+    } else {
+        None
+    })
+}
+
+// Runs hash_attempt() on a watchdog thread when --timeout is given. A read
+// that hangs in the kernel (or a page fault on a mapped file over a dead NFS
+// mount) can't be interrupted, so on timeout we abandon the thread and move
+// on. It will finish or stay stuck in the background, but it won't stall the
+// rest of the run.
+fn hash_attempt_with_timeout(
+    path: &Path,
+    args: &'static Args,
+    attempt: u32,
+) -> Result<Option<HashOutput>> {
+    let timeout = match args.timeout()? {
+        Some(timeout) => timeout,
+        None => return hash_attempt(path, args, attempt),
+    };
+    let (sender, receiver) = mpsc::channel();
+    let path_buf = path.to_path_buf();
+    thread::spawn(move || {
+        // The receiver is gone if we already timed out.
+        let _ = sender.send(hash_attempt(&path_buf, args, attempt));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {:?}", timeout),
+        )
+        .into()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("Hashing thread died"),
     }
-    if args.no_names() {
-        let hash = write_hex_output(output, args);
-        println!();
-        return hash;
+}
+
+// Hashes a file, rehashing it up to --unstable-retries times if its stat
+// changed underneath us, and up to --read-retries times after a transient
+// read error. Returns None if it never held still. Every extra attempt is
+// counted in `retries`.
+fn hash_stable(path: &Path, args: &'static Args, retries: &mut u32) -> Result<Option<HashOutput>> {
+    retry_attempts(path, args, retries, |attempt| {
+        hash_attempt_with_timeout(path, args, attempt)
+    })
+}
+
+// The retry loop of hash_stable, taking the attempt as a closure so that the
+// tests can stand in a read that never succeeds.
+fn retry_attempts(
+    path: &Path,
+    args: &Args,
+    retries: &mut u32,
+    mut attempt: impl FnMut(u32) -> Result<Option<HashOutput>>,
+) -> Result<Option<HashOutput>> {
+    let mut unstable = 0;
+    let mut failed = 0;
+    loop {
+        match attempt(unstable + failed) {
+            Ok(Some(output)) => return Ok(Some(output)),
+            Ok(None) if unstable < args.unstable_retries()? => unstable += 1,
+            Ok(None) => return Ok(None),
+            Err(e) if is_transient(&e) && failed < args.read_retries()? => {
+                eprintln!("{}: {}: {}, retrying", NAME, path.display(), e);
+                thread::sleep(backoff(failed));
+                failed += 1;
+            }
+            Err(e) => return Err(e),
+        }
+        *retries += 1;
     }
-    if filepath_to_string(path) {
-        print!("\\");
+}
+
+fn hash_one_input(path: &Path, args: &'static Args) -> Entry {
+    let mut retries = 0;
+    let before = FileStamp::of(path).ok().flatten();
+    let status = hash_one_status(path, args, &mut retries);
+    let after = FileStamp::of(path).ok().flatten();
+    let stamp = match (before, after) {
+        (Some(before), Some(after)) if status.is_hashed() && before == after => Some(after),
+        _ => None,
+    };
+    Entry {
+        status,
+        retries,
+        stamp,
     }
-    write_hex_output(output, args)
+}
+
+fn hash_one_status(path: &Path, args: &'static Args, retries: &mut u32) -> Status {
+    hashed_status(path, args, hash_stable(path, args, retries))
+}
+
+fn hashed_status(path: &Path, args: &Args, result: Result<Option<HashOutput>>) -> Status {
+    let output = match result {
+        Ok(Some(output)) => output,
+        Ok(None) => {
+            eprintln!("{}: {}: changed while being hashed", NAME, path.display());
+            return Status::Unstable;
+        }
+        Err(e) => {
+            eprintln!("{}: {}: {}", NAME, path.display(), e);
+            return Status::Error(error_kind(&e));
+        }
+    };
+    Status::Hashed(write_hex_output(output, args))
 }
 
 // Returns true for success. Having a boolean return value here, instead of
 // passing down the some_file_failed reference, makes it less likely that we
 // might forget to set it in some error condition.
-fn check_one_line(line: &str, args: &Args) -> bool {
-    let parse_result = parse_check_line(line);
+fn check_one_line(line: &str, args: &Args, report: &mut Report) -> Result<bool> {
+    let parse_result = parse_check_line(line, args.algo());
     let ParsedCheckLine {
         file_string,
         is_escaped,
         file_path,
+        algo,
         expected_hash,
     } = match parse_result {
         Ok(parsed) => parsed,
         Err(e) => {
             eprintln!("{}: {}", NAME, e);
-            return false;
+            return Ok(false);
         }
     };
+    let expected = hex::encode(&expected_hash);
     let file_string = if is_escaped {
         "\\".to_string() + &file_string
     } else {
         file_string
     };
-    let hash_result: Result<blake3::Hash> = Input::open(&file_path, args)
-        .and_then(|mut input| input.hash(args))
-        .map(|mut hash_output| {
-            let mut found_hash_bytes = [0; blake3::OUT_LEN];
-            hash_output.fill(&mut found_hash_bytes);
-            found_hash_bytes.into()
-        });
-    let found_hash: blake3::Hash = match hash_result {
+    let algo = algo.unwrap_or(args.algo());
+    let hash_result = check_hasher(algo, args)
+        .and_then(|hasher| Input::open(&file_path, args)?.hash_with(args, hasher))
+        .map(|output| output.bytes(expected_hash.len()));
+    let found_hash = match hash_result {
         Ok(hash) => hash,
         Err(e) => {
-            println!("{}: FAILED ({})", file_string, e);
-            return false;
+            if report.is_text() {
+                println!("{}: FAILED ({})", file_string, e);
+            }
+            let kind = error_kind(&e);
+            report.record(&file_string, "error", Some(&expected), None, Some(&kind))?;
+            return Ok(false);
         }
     };
-    // This is a constant-time comparison.
-    if expected_hash == found_hash {
-        if !args.quiet() {
+    let found = hex::encode(&found_hash);
+    let matches = constant_time_eq(&expected_hash, &found_hash);
+    if report.is_text() {
+        if !matches {
+            println!("{}: FAILED", file_string);
+        } else if !args.quiet() {
             println!("{}: OK", file_string);
         }
-        true
-    } else {
-        println!("{}: FAILED", file_string);
-        false
     }
+    let status = if matches { "ok" } else { "mismatch" };
+    report.record(&file_string, status, Some(&expected), Some(&found), None)?;
+    Ok(matches)
 }
 
-fn check_one_checkfile(path: &Path, args: &Args, some_file_failed: &mut bool) -> Result<()> {
+// A tagged line for some other algorithm gets a plain hasher for it. Keyed
+// and derive-key hashers only make sense for the --algo they were set up for.
+fn check_hasher(algo: Algo, args: &Args) -> Result<Box<dyn Digest>> {
+    if algo == args.algo() {
+        return Ok(args.base_hasher.clone());
+    }
+    ensure!(
+        !args.keyed() && !args.derive_key(),
+        "--keyed and --derive-key only apply to {}",
+        args.algo().tag()
+    );
+    Ok(algo.hasher())
+}
+
+// Compares digests without returning early at the first difference, so that
+// keyed hashes don't leak how much of them matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn check_one_checkfile(
+    path: &Path,
+    args: &Args,
+    report: &mut Report,
+    some_file_failed: &mut bool,
+) -> Result<()> {
     let checkfile_input = Input::open(path, args)?;
     let mut bufreader = io::BufReader::new(checkfile_input);
     let mut line = String::new();
@@ -584,124 +1871,534 @@ fn check_one_checkfile(path: &Path, args: &Args, some_file_failed: &mut bool) ->
         {
             return Ok(());
         }
+        if interrupted() {
+            return Ok(());
+        }
+        // Comments, like the marker on a partial manifest. No checkfile line
+        // starts with `#`, and sha256sum -c skips them too.
+        if line.starts_with('#') {
+            continue;
+        }
         // check_one_line() prints errors and turns them into a success=false
-        if !check_one_line(&line, args) {
+        if !check_one_line(&line, args, report)? {
             *some_file_failed = true;
         }
     }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse()?;
-    if args.verify { hash_verify(); std::process::exit(0) }
-    let mut thread_pool_builder = rayon::ThreadPoolBuilder::new();
-    if let Some(num_threads) = args.num_threads()? {
-        thread_pool_builder = thread_pool_builder.num_threads(num_threads);
+// Hashes every file under the inputs, and every file in --files-from,
+// passing each entry to `sink` in walk order as soon as it's hashed. Files
+// that `resumed` already has a good hash for aren't read again, and with
+// --sort, the files it got through aren't walked again. Errors hashing
+// files are tolerated and printed to stderr as we go, and recorded both in the
+// entries and in the returned summary. Only an unreadable --files-from list,
+// or an error from `sink`, is fatal. Stops early if the run is interrupted.
+fn hash_inputs(
+    args: &'static Args,
+    resumed: Option<&Resumed>,
+    mut sink: impl FnMut(&Path, String, Entry) -> Result<()>,
+) -> Result<ErrorSummary> {
+    let mut errors = ErrorSummary::default();
+    // A sorted run that got partway replays what it got through from the
+    // checkpoint, and walks on from there.
+    let done = resumed.and_then(|resumed| resumed.done(args));
+    // A --files-from list can't be scanned ahead of time, since it may be a
+    // pipe. The replayed files aren't scanned or counted either.
+    let progress = args.progress().then(|| {
+        let scan = args
+            .files_from()
+            .is_none()
+            .then(|| Scan::Inputs(done.clone()));
+        Progress::start(args, scan)
+    });
+    if let (Some(resumed), Some(done)) = (resumed, &done) {
+        for (path, key, entry) in resumed.replay(args, done) {
+            if interrupted() {
+                return Ok(errors);
+            }
+            let entry = entry.unwrap_or_else(|| hash_one_input(&path, args));
+            errors.record(&entry.status);
+            sink(&path, key, entry)?;
+        }
     }
-    let thread_pool = thread_pool_builder.build()?;
-    thread_pool.install(|| {
-        let mut some_file_failed = false;
-        // Note that file_args automatically includes `-` if nothing is given.
-        let mut list: HashMap<String, String> = HashMap::new();
-        if args.file_args[0].is_dir() {
-            for entry in WalkDir::new(&args.file_args[0])
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.file_type().is_file())
-            {
-                if args.check() {
-                    // A hash mismatch or a failure to read a hashed file will be
-                    // printed in the checkfile loop, and will not propagate here.
-                    // This is similar to the explicit error handling we do in the
-                    // hashing case immediately below. In these cases,
-                    // some_file_failed will be set to false.
-                    check_one_checkfile(entry.path(), &args, &mut some_file_failed)?;
-                } else {
-                    // Errors encountered in hashing are tolerated and printed to
-                    // stderr. This allows e.g. `b3sum *` to print errors for
-                    // non-files and keep going. However, if we encounter any
-                    // errors we'll still return non-zero at the end.
-                    list.insert(
-                        entry.path().to_string_lossy().into_owned(),
-                        hash_one_input(entry.path(), &args),
-                    );
+    for walked in walk::walk_inputs(args, done.as_deref())? {
+        if interrupted() {
+            break;
+        }
+        let (path, entry) = match walked? {
+            // walk_inputs() only yields files.
+            Walked::Other(_) => continue,
+            Walked::File(path) => {
+                if let Some(progress) = &progress {
+                    progress.start_file(&path);
                 }
+                let reused = resumed.and_then(|resumed| resumed.reuse(&path));
+                let skipped = reused.is_some();
+                let entry = reused.unwrap_or_else(|| hash_one_input(&path, args));
+                if let Some(progress) = &progress {
+                    let len = match &entry.stamp {
+                        Some(stamp) => stamp.len,
+                        None => fs::metadata(&path).map_or(0, |metadata| metadata.len()),
+                    };
+                    if skipped {
+                        progress.skip_file(len);
+                    } else {
+                        progress.finish_file(len);
+                    }
+                }
+                (Some(path), entry)
             }
-        } else {
-            let entry = &args.file_args[0];
-            if args.check() {
-                // A hash mismatch or a failure to read a hashed file will be
-                // printed in the checkfile loop, and will not propagate here.
-                // This is similar to the explicit error handling we do in the
-                // hashing case immediately below. In these cases,
-                // some_file_failed will be set to false.
-                check_one_checkfile(&entry, &args, &mut some_file_failed)?;
+            Walked::Error(e) => {
+                let path = e.path().map(Path::to_path_buf);
+                let e = anyhow::Error::from(e);
+                eprintln!("{}: {}", NAME, e);
+                let status = Status::Error(error_kind(&e));
+                let entry = Entry {
+                    status,
+                    retries: 0,
+                    stamp: None,
+                };
+                (path, entry)
+            }
+        };
+        errors.record(&entry.status);
+        if let Some(path) = path {
+            sink(&path, filepath_to_string(&path), entry)?;
+        }
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    Ok(errors)
+}
+
+// Writes the manifest, or with --raw, the raw hash of the single input.
+// Returns true if any file couldn't be hashed.
+fn hash_main(args: &'static Args) -> Result<bool> {
+    if args.raw() {
+        let mut retries = 0;
+        match hash_stable(&args.file_args[0], args, &mut retries)? {
+            Some(output) => write_raw_output(output, args)?,
+            None => bail!("{} changed while being hashed", args.file_args[0].display()),
+        }
+        return Ok(false);
+    }
+    let format = args.format();
+    ensure!(
+        !(format == Format::Mtree && args.resume()),
+        "--resume doesn't work with --format mtree"
+    );
+    let mut output = Output::open(args.output_path())?;
+    let (resumed, mut checkpoint) = match args.output_path() {
+        Some(path) if path != Path::new("-") && format != Format::Mtree => {
+            let resumed = if args.resume() {
+                Some(Resumed::load(path, args)?)
             } else {
-                // Errors encountered in hashing are tolerated and printed to
-                // stderr. This allows e.g. `b3sum *` to print errors for
-                // non-files and keep going. However, if we encounter any
-                // errors we'll still return non-zero at the end.
-                list.insert(
-                    entry.to_string_lossy().into_owned(),
-                    hash_one_input(&entry, &args),
-                );
+                None
+            };
+            let checkpoint = Checkpoint::create(path, args)?;
+            (resumed, Some(checkpoint))
+        }
+        _ => (None, None),
+    };
+    ensure!(
+        !(format == Format::Gnu && args.algos.len() > 1),
+        "--format gnu takes a single --algo, but bsd can hold several"
+    );
+    if format == Format::Hashdeep {
+        hashdeep::write_header(&mut output, &args.algos)?;
+    }
+    let mut json = match format {
+        Format::Text | Format::Gnu | Format::Bsd | Format::Hashdeep | Format::Mtree => None,
+        _ => Some(ManifestWriter::start(
+            &mut output,
+            format,
+            &args.algos,
+            args.len()? as usize,
+        )?),
+    };
+    let errors = if format == Format::Mtree {
+        mtree::write_spec(args, &mut output)?
+    } else {
+        hash_inputs(args, resumed.as_ref(), |path, key, entry| {
+            if let Some(json) = &mut json {
+                json.entry(&mut output, path, &key, &entry)?;
+            } else if args.no_names() {
+                writeln!(output, "{}", entry)?;
+            } else if matches!(format, Format::Gnu | Format::Bsd) {
+                write_checksum_lines(&mut output, format, &args.algos, &key, &entry)?;
+            } else if format == Format::Hashdeep {
+                hashdeep::write_entry(&mut output, path, &key, &entry)?;
+            } else {
+                write_manifest_line(&mut output, &key, &entry)?;
             }
+            if let Some(checkpoint) = &mut checkpoint {
+                checkpoint.record(&key, &entry, resumed.as_ref())?;
+            }
+            Ok(())
+        })?
+    };
+    if let Some(json) = json {
+        json.finish(&mut output, interrupted())?;
+    } else if interrupted() {
+        writeln!(output, "{}", PARTIAL_MARKER)?;
+    }
+    if interrupted() {
+        match output.finish_partial()? {
+            Some(path) => eprintln!("{}: wrote partial manifest to {}", NAME, path.display()),
+            None => eprintln!("{}: the manifest above is partial", NAME),
         }
-        // write the hashmap to a file
-        let mut file = File::create(&args.output_path)?;
-        for (path, hash) in list {
-            writeln!(file, "{}:{}", path, hash)?;
+        if let Some(checkpoint) = checkpoint {
+            checkpoint.sync()?;
+            eprintln!("{}: rerun with --resume to continue", NAME);
         }
-        std::process::exit(if some_file_failed { 1 } else { 0 });
-    })
+        errors.report();
+        return Ok(true);
+    }
+    output.finish(args.backup())?;
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.finish()?;
+    }
+    errors.report();
+    Ok(!errors.is_empty())
 }
 
-fn hash_verify() {
-    let args = Args::parse().unwrap();
-    let input = args.file_args[0].to_string_lossy().into_owned();
-    let check = args.output_path.to_string_lossy().into_owned();
-    let mut list_input: HashMap<String, String> = HashMap::new();
-    let mut list_check: HashMap<String, String> = HashMap::new();
-
-    let mut file_input = File::open(&input).unwrap();
-    let reader_input = BufReader::new(&mut file_input).lines();
-    let mut file_check = File::open(&check).unwrap();
-    let reader_check = BufReader::new(&mut file_check).lines();
-
-    // parse the input file and insert back into hashmap
-    for line in reader_input {
-        let line = line.unwrap();
-        let mut split = line.split(":");
-        let path = split.next().unwrap();
-        let hash = split.next().unwrap();
-        list_input.insert(path.to_string(), hash.to_string());
-    }
-    // parse the check file and insert back into hashmap
-    for line in reader_check {
-        let line = line.unwrap();
-        let mut split = line.split(':');
-        let path = split.next().unwrap();
-        let hash = split.next().unwrap();
-        list_check.insert(path.to_string(), hash.to_string());
-    }
-
-    // match hashmaps
-    for entry in list_check.keys() {
-        // if entry for file doesn't exist in input, print error
-        if !list_input.contains_key(entry) {
-            println!("{}: NO EXIST", entry);
-            continue;
-        // if input hash doesn't match check hash, print error
-        } else if list_input[entry] != list_check[entry] {
-            println!("{}: MISMATCH", entry);
-            continue;
-        /*} else if !list_input.get(entry).unwrap().eq(list_check.get(entry).unwrap()) {
-            println!("{}: MISMATCH", entry);
-            continue;
-        */} else {
-            //println!("{}: OK", entry);
-            continue;
+// Returns true if the tree doesn't match the manifest.
+fn verify_main(args: &'static Args) -> Result<bool> {
+    let mut report = Report::start(args.format())?;
+    if let Some(path) = args.html_path() {
+        let title = format!("dirhash verify against {}", args.manifest_path().display());
+        let old = Metadata::from_manifest(args.manifest_path())?;
+        report.html(Html::new(path, title, old, Metadata::Disk));
+    }
+    if mtree::is_spec_file(args.manifest_path()) {
+        ensure!(
+            !args.sort() && !args.audit(),
+            "--sort and --audit don't work with mtree specs"
+        );
+        let (differs, errors) = mtree::verify(args, &mut report)?;
+        report.finish(interrupted())?;
+        errors.report();
+        return Ok(differs || !errors.is_empty());
+    }
+    if args.sort() {
+        let (differs, errors) = sorted::verify_sorted(args, &mut report)?;
+        report.finish(interrupted())?;
+        errors.report();
+        return Ok(differs || !errors.is_empty());
+    }
+    let expected = read_manifest(args.manifest_path(), Some(&args.algos))?;
+    let mut found = HashMap::new();
+    let errors = hash_inputs(args, None, |_, key, entry| {
+        found.insert(key, entry.status.to_string());
+        Ok(())
+    })?;
+    // Everything we didn't get to would show up as MISSING.
+    if interrupted() {
+        report.finish(true)?;
+        return Ok(true);
+    }
+    let differs = if args.audit() {
+        audit::audit_manifests(&expected, &found, &mut report)?
+    } else {
+        diff_manifests(&expected, &found, &mut report)?
+    };
+    report.finish(false)?;
+    errors.report();
+    Ok(differs || !errors.is_empty())
+}
+
+// Returns true if any checked file failed.
+fn check_main(args: &Args) -> Result<bool> {
+    let mut report = Report::start(args.format())?;
+    let mut some_file_failed = false;
+    for path in &args.file_args {
+        if interrupted() {
+            break;
         }
+        // A hash mismatch or a failure to read a hashed file will be printed
+        // in the checkfile loop, and will not propagate here. In these cases,
+        // some_file_failed will be set to true.
+        check_one_checkfile(path, args, &mut report, &mut some_file_failed)?;
     }
-    std::process::exit(0); // Add error handling later
-}
\ No newline at end of file
+    report.finish(interrupted())?;
+    Ok(some_file_failed)
+}
+
+fn main() -> Result<()> {
+    // Leaked so that the watchdog threads --timeout hashes on can borrow it.
+    let args: &'static Args = Box::leak(Box::new(Args::parse()?));
+    if args.mode == Mode::Diff {
+        let mut report = Report::start(args.format())?;
+        if let Some(path) = args.html_path() {
+            let [old, new] = [&args.file_args[0], &args.file_args[1]];
+            let title = format!("dirhash diff {} {}", old.display(), new.display());
+            let old = Metadata::from_manifest(old)?;
+            let new = Metadata::from_manifest(new)?;
+            report.html(Html::new(path, title, old, new));
+        }
+        let differs = if args.sorted() {
+            sorted::diff_sorted(&args.file_args[0], &args.file_args[1], &mut report)?
+        } else {
+            let old = read_manifest(&args.file_args[0], None)?;
+            let new = read_manifest(&args.file_args[1], None)?;
+            if args.audit() {
+                audit::audit_manifests(&old, &new, &mut report)?
+            } else {
+                diff_manifests(&old, &new, &mut report)?
+            }
+        };
+        report.finish(false)?;
+        std::process::exit(if differs { 1 } else { 0 });
+    }
+    let mut thread_pool_builder = rayon::ThreadPoolBuilder::new();
+    if let Some(num_threads) = args.num_threads()? {
+        thread_pool_builder = thread_pool_builder.num_threads(num_threads);
+    }
+    // Parse these up front, rather than failing on the first file.
+    args.unstable_retries()?;
+    args.read_retries()?;
+    args.timeout()?;
+    args.len()?;
+    // We configure the global pool, rather than installing our own, so that
+    // update_rayon() on the watchdog threads uses it too.
+    thread_pool_builder.build_global()?;
+    handle_interrupts()?;
+    let some_file_failed = match args.mode {
+        Mode::Hash => hash_main(args)?,
+        Mode::Verify => verify_main(args)?,
+        Mode::Check => check_main(args)?,
+        Mode::BagCreate => bag::create(args)?,
+        Mode::BagValidate => bag::validate(args)?,
+        Mode::GoMod => gomod::main(args)?,
+        Mode::CargoChecksum => cargo_checksum::main(args)?,
+        Mode::Diff => unreachable!(),
+    };
+    args.sparse_stats.report();
+    if interrupted() {
+        std::process::exit(INTERRUPTED_STATUS);
+    }
+    std::process::exit(if some_file_failed { 1 } else { 0 });
+}
+
+// Compares two manifests, printing every path that differs. Returns true if
+// there were any differences.
+fn diff_manifests(
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+    report: &mut Report,
+) -> Result<bool> {
+    let mut paths: Vec<&String> = old
+        .keys()
+        .chain(new.keys().filter(|p| !old.contains_key(*p)))
+        .collect();
+    paths.sort();
+    let mut differs = false;
+    for path in paths {
+        differs |= report_diff(
+            report,
+            path,
+            old.get(path).map(String::as_str),
+            new.get(path).map(String::as_str),
+        )?;
+    }
+    Ok(differs)
+}
+
+// Prints what changed about a path, given its value in the old and new
+// manifests (None if it isn't in one of them). Returns true if anything did.
+fn report_diff(
+    report: &mut Report,
+    path: &str,
+    old: Option<&str>,
+    new: Option<&str>,
+) -> Result<bool> {
+    let (status, label) = match (old, new) {
+        (Some(_), None) => ("missing", "MISSING"),
+        (None, Some(_)) => ("new", "NEW"),
+        // if either side couldn't be hashed reliably, there's nothing to compare
+        (Some(old), Some(_)) if !is_hash_value(old) => (value_status(old), old),
+        (Some(_), Some(new)) if !is_hash_value(new) => (value_status(new), new),
+        (Some(old), Some(new)) if old != new => ("mismatch", "MISMATCH"),
+        _ => ("ok", "OK"),
+    };
+    let error = label
+        .strip_prefix("ERROR(")
+        .and_then(|kind| kind.strip_suffix(')'));
+    report.record(path, status, old, new, error)?;
+    if status == "ok" {
+        return Ok(false);
+    }
+    if report.is_text() {
+        println!("{}: {}", path, label);
+    }
+    Ok(true)
+}
+
+// The report status for a manifest value that isn't a hash.
+fn value_status(value: &str) -> &'static str {
+    if value == Status::Unstable.to_string() {
+        "unstable"
+    } else {
+        "error"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn parses_gnu_lines() {
+        let parsed = parse_check_line(&format!("{}  a b.txt\n", SHA256_ABC), Algo::Sha256).unwrap();
+        assert_eq!(parsed.file_path, Path::new("a b.txt"));
+        assert_eq!(parsed.algo, None);
+        assert_eq!(parsed.expected_hash, hex::decode(SHA256_ABC).unwrap());
+        assert!(!parsed.is_escaped);
+
+        let binary = parse_check_line(&format!("{} *bin", SHA256_ABC), Algo::Sha256).unwrap();
+        assert_eq!(binary.file_path, Path::new("bin"));
+    }
+
+    #[test]
+    fn parses_bsd_lines() {
+        let line = format!("SHA256 (odd) = name) = {}", SHA256_ABC);
+        let parsed = parse_check_line(&line, Algo::Blake3).unwrap();
+        assert_eq!(parsed.algo, Some(Algo::Sha256));
+        assert_eq!(parsed.file_path, Path::new("odd) = name"));
+        assert_eq!(parsed.expected_hash, hex::decode(SHA256_ABC).unwrap());
+
+        // BLAKE3 lines may be any even length, from --length.
+        let parsed = parse_check_line("BLAKE3 (f) = 00ff", Algo::Sha256).unwrap();
+        assert_eq!(parsed.expected_hash, vec![0x00, 0xff]);
+    }
+
+    #[test]
+    fn parses_escaped_lines() {
+        let line = format!("\\{}  new\\nline\\\\back", SHA256_ABC);
+        let parsed = parse_check_line(&line, Algo::Sha256).unwrap();
+        assert!(parsed.is_escaped);
+        assert_eq!(parsed.file_string, "new\\nline\\\\back");
+        assert_eq!(parsed.file_path, Path::new("new\nline\\back"));
+
+        let tag = format!("\\SHA256 (a\\rb) = {}", SHA256_ABC);
+        let parsed = parse_check_line(&tag, Algo::Blake3).unwrap();
+        assert_eq!(parsed.file_path, Path::new("a\rb"));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let sha256 = |line: &str| parse_check_line(line, Algo::Sha256);
+        assert!(sha256("").is_err());
+        assert!(sha256(&SHA256_ABC[..10]).is_err());
+        assert!(sha256(&format!("{} x", SHA256_ABC)).is_err());
+        assert!(sha256(&format!("{}  ", SHA256_ABC)).is_err());
+        assert!(sha256(&format!("{}zz  f", &SHA256_ABC[..62])).is_err());
+        assert!(sha256(&format!("\\{}  bad\\escape", SHA256_ABC)).is_err());
+        assert!(sha256(&format!("SHA256 (f) = {}", &SHA256_ABC[..62])).is_err());
+        assert!(sha256(&format!("SHA256 () = {}", SHA256_ABC)).is_err());
+        assert!(sha256(&format!("SHA256 (f) {}", SHA256_ABC)).is_err());
+        assert!(sha256("BLAKE3 (f) = abc").is_err());
+    }
+
+    #[test]
+    fn rejects_non_ascii_hashes() {
+        // The right length in bytes, but not in characters.
+        let tag = format!("SHA256 (f) = {}é", "a".repeat(62));
+        assert!(parse_check_line(&tag, Algo::Sha256).is_err());
+        let gnu = format!("{}é  f", "a".repeat(62));
+        assert!(parse_check_line(&gnu, Algo::Sha256).is_err());
+        let blake3 = "BLAKE3 (f) = aé";
+        assert!(parse_check_line(blake3, Algo::Blake3).is_err());
+    }
+
+    #[test]
+    fn sparse_files_hash_like_dense_ones() {
+        let path = std::env::temp_dir().join(format!("dirhash-sparse-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        // A hole, some data, a bigger hole, more data, and a trailing hole.
+        file.set_len(10 << 20).unwrap();
+        file.seek(io::SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(&[0xab; 5000]).unwrap();
+        file.seek(io::SeekFrom::Start(6 << 20)).unwrap();
+        file.write_all(b"in the middle").unwrap();
+        drop(file);
+
+        for algo in [Algo::Blake3, Algo::Sha256] {
+            let mut file = File::open(&path).unwrap();
+            let mut sparse = algo.hasher();
+            copy_sparse(&mut file, 10 << 20, &mut *sparse).unwrap();
+            let mut dense = algo.hasher();
+            copy_wide(File::open(&path).unwrap(), &mut *dense).unwrap();
+            assert_eq!(
+                sparse.finalize().hex(algo.output_len()),
+                dense.finalize().hex(algo.output_len()),
+                "{}",
+                algo.name()
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stamps_notice_changed_files() {
+        let dir = std::env::temp_dir().join(format!("dirhash-stamp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, "before").unwrap();
+        let stamp = || FileStamp::of(&path).unwrap().unwrap();
+        let original = stamp();
+        assert!(stamp() == original);
+
+        // A different length.
+        fs::write(&path, "before, and after").unwrap();
+        let longer = stamp();
+        assert!(longer.len != original.len);
+        assert!(longer != original);
+
+        // The same length, with only the mtime moved.
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        drop(file);
+        let touched = stamp();
+        assert_eq!(touched.len, longer.len);
+        assert!(touched.modified != longer.modified);
+        assert!(touched != longer);
+
+        // Another file renamed over it, with the same length and mtime.
+        let other = dir.join("other");
+        fs::write(&other, "before, and after").unwrap();
+        let file = fs::OpenOptions::new().write(true).open(&other).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        drop(file);
+        fs::rename(&other, &path).unwrap();
+        let replaced = stamp();
+        assert_eq!(replaced.len, touched.len);
+        assert_eq!(replaced.modified, touched.modified);
+        if cfg!(unix) {
+            assert!(replaced.inode != touched.inode);
+            assert!(replaced != touched);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_reads_are_retried_then_recorded() {
+        let argv = ["dirhash", "hash", "-i", "x", "--read-retries", "2"];
+        let args = &Args::parse_from(argv.iter().map(OsString::from)).unwrap();
+        let mut attempts = Vec::new();
+        let mut retries = 0;
+        let result = retry_attempts(Path::new("x"), args, &mut retries, |attempt| {
+            attempts.push(attempt);
+            Err(io::Error::from_raw_os_error(libc::EIO).into())
+        });
+        assert_eq!(attempts, [0, 1, 2]);
+        assert_eq!(retries, 2);
+        let status = hashed_status(Path::new("x"), args, result);
+        let kind = io::Error::from_raw_os_error(libc::EIO).kind();
+        assert_eq!(status.to_string(), format!("ERROR({:?})", kind));
+    }
+}
@@ -0,0 +1,905 @@
+//! mtree specs, as read and written by `mtree(8)` and
+//! `bsdtar --format=mtree`. `hash --format mtree` writes one for a directory,
+//! with a full path on every line like `mtree -C`, and `verify` checks a live
+//! directory against one, in either that layout or the classic one that
+//! changes directory as it goes.
+
+use anyhow::{ensure, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::algo::Algo;
+use crate::format::Report;
+use crate::progress::{Progress, Scan};
+use crate::walk::{self, Walked};
+use crate::{
+    error_kind, filepath_to_string, hash_one_input, interrupted, Args, Entry, ErrorSummary, Status,
+    NAME,
+};
+
+// mtree only has keywords for some of our algorithms.
+fn keyword(algo: Algo) -> Option<&'static str> {
+    match algo {
+        Algo::Sha1 => Some("sha1digest"),
+        Algo::Sha256 => Some("sha256digest"),
+        Algo::Sha512 => Some("sha512digest"),
+        _ => None,
+    }
+}
+
+// The digest keywords we can't check. Each has a short alias without
+// "digest", as do the ones we can.
+const UNSUPPORTED_DIGESTS: [&str; 4] = [
+    "md5digest",
+    "rmd160digest",
+    "ripemd160digest",
+    "sha384digest",
+];
+
+fn digest_value<'a>(keywords: &'a HashMap<String, String>, keyword: &str) -> Option<&'a str> {
+    keywords
+        .get(keyword)
+        .or_else(|| keywords.get(keyword.trim_end_matches("digest")))
+        .map(String::as_str)
+}
+
+pub fn check_algos(algos: &[Algo]) -> Result<()> {
+    for &algo in algos {
+        ensure!(
+            keyword(algo).is_some(),
+            "mtree has no digest keyword for {}; use sha256, sha512 or sha1",
+            algo.name()
+        );
+    }
+    Ok(())
+}
+
+// mtree(8) starts its specs with `#\t   user: ...` comments, and libarchive
+// with `#mtree`.
+pub fn is_spec(first_line: &str) -> bool {
+    first_line.starts_with("#mtree") || first_line.starts_with("#\t")
+}
+
+pub fn is_spec_file(path: &Path) -> bool {
+    let mut first_line = String::new();
+    fs::File::open(path)
+        .and_then(|file| BufReader::new(file).read_line(&mut first_line))
+        .is_ok()
+        && is_spec(&first_line)
+}
+
+// For verify without --algo: None if `path` isn't an mtree spec, or else the
+// algorithms of the digests it has, which may be none.
+pub fn spec_algos(path: &Path) -> Result<Option<Vec<Algo>>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        // verify reports this properly when it reads the manifest.
+        Err(_) => return Ok(None),
+    };
+    let mut lines = BufReader::new(file).lines();
+    match lines.next() {
+        Some(Ok(line)) if is_spec(&line) => {}
+        _ => return Ok(None),
+    }
+    let mut found = Vec::new();
+    for line in lines {
+        let line = line?;
+        for word in line.split_whitespace() {
+            let name = word.split('=').next().unwrap();
+            for algo in [Algo::Sha256, Algo::Sha512, Algo::Sha1] {
+                let keyword = keyword(algo).unwrap();
+                let matches = name == keyword || name == keyword.trim_end_matches("digest");
+                if matches && !found.contains(&algo) {
+                    found.push(algo);
+                }
+            }
+        }
+    }
+    Ok(Some(found))
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+// Names are encoded like strsvis(3) does for mtree: whitespace, anything
+// outside printable ASCII, backslashes and glob characters become \ooo.
+fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b <= b' ' || b >= 0x7f || b"\\#*?[=".contains(&b) {
+            encoded += &format!("\\{:03o}", b);
+        } else {
+            encoded.push(b as char);
+        }
+    }
+    encoded
+}
+
+fn decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|digits| digits.iter().all(|d| (b'0'..=b'7').contains(d)));
+        if let Some(digits) = octal {
+            let value = digits
+                .iter()
+                .fold(0u32, |acc, d| acc * 8 + u32::from(d - b'0'));
+            decoded.push(value as u8);
+            i += 4;
+            continue;
+        }
+        decoded.push(match bytes[i + 1] {
+            b's' => b' ',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            other => other,
+        });
+        i += 2;
+    }
+    decoded
+}
+
+// `./sub/name`, relative to the root of the tree.
+fn spec_name(rel: &Path) -> String {
+    let mut name = String::from(".");
+    for component in rel.components() {
+        name.push('/');
+        name += &encode(&path_bytes(Path::new(component.as_os_str())));
+    }
+    name
+}
+
+// The report key for a path relative to the root, in the same `./sub/name`
+// form, but escaped like a manifest key.
+fn report_key(rel: &Path) -> String {
+    if rel.as_os_str().is_empty() {
+        return ".".to_string();
+    }
+    filepath_to_string(&Path::new(".").join(rel))
+}
+
+// What the spec describes about a path on disk.
+struct Meta {
+    kind: &'static str,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    time: (i64, u32),
+    link: Option<PathBuf>,
+}
+
+impl Meta {
+    // Symlinks are described, not followed.
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let link = if file_type.is_symlink() {
+            Some(fs::read_link(path)?)
+        } else {
+            None
+        };
+        #[cfg(unix)]
+        let (mode, uid, gid, time) = {
+            use std::os::unix::fs::MetadataExt;
+            (
+                metadata.mode() & 0o7777,
+                metadata.uid(),
+                metadata.gid(),
+                (metadata.mtime(), metadata.mtime_nsec() as u32),
+            )
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid, time) = {
+            let since = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::SystemTime::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            (0, 0, 0, (since.as_secs() as i64, since.subsec_nanos()))
+        };
+        Ok(Self {
+            kind: kind(&file_type),
+            mode,
+            uid,
+            gid,
+            size: metadata.len(),
+            time,
+            link,
+        })
+    }
+
+    // A file's size and mtime come from the stamp taken around its hash when
+    // there is one, so that they describe the contents that were hashed.
+    fn with_stamp(mut self, entry: &Entry) -> Self {
+        if let Some(stamp) = &entry.stamp {
+            self.size = stamp.len;
+            let since = stamp
+                .modified
+                .and_then(|time| time.duration_since(std::time::SystemTime::UNIX_EPOCH).ok());
+            if let Some(since) = since {
+                self.time = (since.as_secs() as i64, since.subsec_nanos());
+            }
+        }
+        self
+    }
+}
+
+fn kind(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        return "dir";
+    }
+    if file_type.is_symlink() {
+        return "link";
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_block_device() {
+            return "block";
+        }
+        if file_type.is_char_device() {
+            return "char";
+        }
+        if file_type.is_fifo() {
+            return "fifo";
+        }
+        if file_type.is_socket() {
+            return "socket";
+        }
+    }
+    "file"
+}
+
+// Writes the spec for the single directory given with -i. Files that can't
+// be hashed are left out, with the error on stderr, as in the other
+// checksum formats.
+pub fn write_spec(args: &'static Args, mut w: impl Write) -> Result<ErrorSummary> {
+    check_algos(&args.algos)?;
+    ensure!(
+        args.file_args.len() == 1 && args.files_from().is_none() && args.file_args[0].is_dir(),
+        "--format mtree takes a single directory with -i"
+    );
+    let root = &args.file_args[0];
+    writeln!(w, "#mtree v2.0")?;
+    let mut errors = ErrorSummary::default();
+    let progress = args
+        .progress()
+        .then(|| Progress::start(args, Some(Scan::Tree(root.clone()))));
+    for walked in walk::walk_tree(args, root) {
+        if interrupted() {
+            break;
+        }
+        let (path, entry) = match walked? {
+            Walked::File(path) => {
+                if let Some(progress) = &progress {
+                    progress.start_file(&path);
+                }
+                let entry = hash_one_input(&path, args);
+                if let Some(progress) = &progress {
+                    progress.finish_file(entry.stamp.as_ref().map_or(0, |stamp| stamp.len));
+                }
+                errors.record(&entry.status);
+                if !entry.status.is_hashed() {
+                    continue;
+                }
+                (path, Some(entry))
+            }
+            Walked::Other(path) => (path, None),
+            Walked::Error(e) => {
+                let e = anyhow::Error::from(e);
+                eprintln!("{}: {}", NAME, e);
+                errors.record(&Status::Error(error_kind(&e)));
+                continue;
+            }
+        };
+        let meta = match Meta::of(&path) {
+            Ok(meta) => match &entry {
+                Some(entry) => meta.with_stamp(entry),
+                None => meta,
+            },
+            Err(e) => {
+                eprintln!("{}: {}: {}", NAME, path.display(), e);
+                errors.record(&Status::Error(format!("{:?}", e.kind())));
+                continue;
+            }
+        };
+        let rel = path.strip_prefix(root).unwrap_or(&path);
+        let mut line = format!(
+            "{} type={} uid={} gid={} mode={:04o}",
+            spec_name(rel),
+            meta.kind,
+            meta.uid,
+            meta.gid,
+            meta.mode
+        );
+        if entry.is_some() {
+            line += &format!(" size={}", meta.size);
+        }
+        line += &format!(" time={}.{:09}", meta.time.0, meta.time.1);
+        if let Some(Status::Hashed(hashes)) = entry.as_ref().map(|entry| &entry.status) {
+            for (algo, hash) in args.algos.iter().zip(hashes.split(',')) {
+                line += &format!(" {}={}", keyword(*algo).unwrap(), hash);
+            }
+        }
+        if let Some(link) = &meta.link {
+            line += &format!(" link={}", encode(&path_bytes(link)));
+        }
+        writeln!(w, "{}", line)?;
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    Ok(errors)
+}
+
+struct SpecEntry {
+    // Relative to the root of the tree; empty for the root itself.
+    rel: PathBuf,
+    keywords: HashMap<String, String>,
+}
+
+impl SpecEntry {
+    fn has(&self, keyword: &str) -> bool {
+        self.keywords.contains_key(keyword)
+    }
+
+    fn get(&self, keyword: &str) -> Option<&str> {
+        self.keywords.get(keyword).map(String::as_str)
+    }
+}
+
+// A decoded name as a path relative to the root. Names can't leave the tree.
+fn relative_path(name: &[u8]) -> Result<PathBuf> {
+    let path = bytes_path(name.to_vec());
+    let mut rel = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => rel.push(part),
+            Component::CurDir => {}
+            _ => anyhow::bail!("{} is outside the tree", path.display()),
+        }
+    }
+    Ok(rel)
+}
+
+fn parse_keywords<'a>(words: impl Iterator<Item = &'a str>, into: &mut HashMap<String, String>) {
+    for word in words {
+        let (keyword, value) = word.split_once('=').unwrap_or((word, ""));
+        into.insert(keyword.to_string(), value.to_string());
+    }
+}
+
+// Reads both layouts. In the full-path one, every name has a slash in it. In
+// the classic one, a directory entry without a slash changes into that
+// directory, `..` changes back out, and `/set` and `/unset` change the
+// defaults every later entry starts with.
+fn parse_spec(text: &str) -> Result<Vec<SpecEntry>> {
+    let mut entries = Vec::new();
+    let mut defaults = HashMap::new();
+    let mut cwd = PathBuf::new();
+    let mut pending = String::new();
+    for raw in text.lines() {
+        // A trailing backslash continues the entry on the next line, unless
+        // it's the second half of an escaped `\\` at the end of a name.
+        let backslashes = raw.bytes().rev().take_while(|&b| b == b'\\').count();
+        if backslashes % 2 == 1 {
+            pending += &raw[..raw.len() - 1];
+            pending.push(' ');
+            continue;
+        }
+        let line = std::mem::take(&mut pending) + raw;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let first = words.next().unwrap();
+        match first {
+            "/set" => parse_keywords(words, &mut defaults),
+            "/unset" => {
+                for word in words {
+                    if word == "all" {
+                        defaults.clear();
+                    } else {
+                        defaults.remove(word);
+                    }
+                }
+            }
+            ".." => {
+                cwd.pop();
+            }
+            _ => {
+                let mut keywords = defaults.clone();
+                parse_keywords(words, &mut keywords);
+                let name = decode(first);
+                let rel = if name.contains(&b'/') {
+                    relative_path(&name)?
+                } else {
+                    let rel = cwd.join(relative_path(&name)?);
+                    if keywords.get("type").map(String::as_str) == Some("dir") && name != b"." {
+                        cwd = rel.clone();
+                    }
+                    rel
+                };
+                entries.push(SpecEntry { rel, keywords });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// `sec.nsec`. Like mtree(8) and libarchive, the part after the dot is a count
+// of nanoseconds, which libarchive writes without leading zeros, rather than
+// a decimal fraction. Zero or no nanoseconds means the spec didn't record
+// them.
+fn parse_time(value: &str) -> Option<(i64, Option<u32>)> {
+    let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+    let secs = secs.parse().ok()?;
+    let nanos: u32 = nanos.parse().ok()?;
+    Some((secs, (nanos != 0).then_some(nanos.min(999_999_999))))
+}
+
+// The keywords that differ between the spec and the disk, as (keyword,
+// expected, found).
+fn compare_meta(entry: &SpecEntry, meta: &Meta) -> Vec<(&'static str, String, String)> {
+    let mut diffs = Vec::new();
+    if let Some(kind) = entry.get("type") {
+        if kind != meta.kind {
+            diffs.push(("type", kind.to_string(), meta.kind.to_string()));
+            return diffs;
+        }
+    }
+    if cfg!(unix) {
+        if let Some(mode) = entry
+            .get("mode")
+            .and_then(|m| u32::from_str_radix(m, 8).ok())
+        {
+            if mode & 0o7777 != meta.mode {
+                diffs.push((
+                    "mode",
+                    format!("{:04o}", mode),
+                    format!("{:04o}", meta.mode),
+                ));
+            }
+        }
+        for (keyword, found) in [("uid", meta.uid), ("gid", meta.gid)] {
+            if let Some(id) = entry.get(keyword).and_then(|id| id.parse::<u32>().ok()) {
+                if id != found {
+                    diffs.push((keyword, id.to_string(), found.to_string()));
+                }
+            }
+        }
+    }
+    if meta.kind == "file" {
+        if let Some(size) = entry.get("size").and_then(|size| size.parse::<u64>().ok()) {
+            if size != meta.size {
+                diffs.push(("size", size.to_string(), meta.size.to_string()));
+            }
+        }
+    }
+    if let Some((secs, nanos)) = entry.get("time").and_then(parse_time) {
+        if secs != meta.time.0 || nanos.is_some_and(|nanos| nanos != meta.time.1) {
+            diffs.push((
+                "time",
+                entry.get("time").unwrap().to_string(),
+                format!("{}.{:09}", meta.time.0, meta.time.1),
+            ));
+        }
+    }
+    if let (Some(expected), Some(found)) = (entry.get("link"), &meta.link) {
+        if bytes_path(decode(expected)) != *found {
+            diffs.push(("link", expected.to_string(), encode(&path_bytes(found))));
+        }
+    }
+    diffs
+}
+
+fn report_line(
+    report: &mut Report,
+    key: &str,
+    status: &'static str,
+    expected: Option<&str>,
+    found: Option<&str>,
+    error: Option<&str>,
+    detail: &str,
+) -> Result<bool> {
+    report.record(key, status, expected, found, error)?;
+    if status == "ok" {
+        return Ok(false);
+    }
+    if report.is_text() {
+        if detail.is_empty() {
+            println!("{}: {}", key, status.to_uppercase());
+        } else {
+            println!("{}: {} ({})", key, status.to_uppercase(), detail);
+        }
+    }
+    Ok(true)
+}
+
+// Checks the single -i directory against the spec at --manifest. Entries
+// marked `optional` may be missing, `nochange` only has to exist, and
+// `ignore` leaves whatever is under a directory unchecked. Anything on disk
+// the spec doesn't mention is NEW. Returns true if anything differs.
+pub fn verify(args: &'static Args, report: &mut Report) -> Result<(bool, ErrorSummary)> {
+    ensure!(
+        args.file_args.len() == 1 && args.files_from().is_none() && args.file_args[0].is_dir(),
+        "Verifying against an mtree spec takes a single directory with -i"
+    );
+    check_algos(&args.algos)?;
+    let root = &args.file_args[0];
+    let manifest = args.manifest_path();
+    let text = fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {}", manifest.display()))?;
+    let spec =
+        parse_spec(&text).with_context(|| format!("Failed to parse {}", manifest.display()))?;
+    for unsupported in UNSUPPORTED_DIGESTS {
+        let short = unsupported.trim_end_matches("digest");
+        if spec
+            .iter()
+            .any(|entry| entry.has(unsupported) || entry.has(short))
+        {
+            eprintln!(
+                "{}: {} isn't supported, so it isn't checked",
+                NAME, unsupported
+            );
+        }
+    }
+
+    let mut errors = ErrorSummary::default();
+    let mut differs = false;
+    let mut described = BTreeSet::new();
+    let mut ignored = Vec::new();
+    let progress = args
+        .progress()
+        .then(|| Progress::start(args, Some(Scan::Tree(root.clone()))));
+    let mut by_path: BTreeMap<&Path, &SpecEntry> = BTreeMap::new();
+    for entry in &spec {
+        by_path.insert(&entry.rel, entry);
+    }
+    for (rel, entry) in by_path {
+        if interrupted() {
+            break;
+        }
+        described.insert(rel.to_path_buf());
+        if entry.has("ignore") {
+            ignored.push(rel.to_path_buf());
+        }
+        let key = report_key(rel);
+        let path = root.join(rel);
+        let meta = match Meta::of(&path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !entry.has("optional") {
+                    differs |= report_line(report, &key, "missing", None, None, None, "")?;
+                }
+                continue;
+            }
+            Err(e) => {
+                let kind = format!("{:?}", e.kind());
+                eprintln!("{}: {}: {}", NAME, path.display(), e);
+                differs |= report_line(report, &key, "error", None, None, Some(&kind), "")?;
+                continue;
+            }
+        };
+        if entry.has("nochange") {
+            report_line(report, &key, "ok", None, None, None, "")?;
+            continue;
+        }
+        let diffs = compare_meta(entry, &meta);
+
+        // Only files the spec has a digest for are hashed, once for all of
+        // them.
+        let digests: Vec<(usize, &str)> = args
+            .algos
+            .iter()
+            .enumerate()
+            .filter_map(|(i, algo)| Some((i, digest_value(&entry.keywords, keyword(*algo)?)?)))
+            .collect();
+        if meta.kind == "file" && !digests.is_empty() && diffs.iter().all(|d| d.0 != "type") {
+            if let Some(progress) = &progress {
+                progress.start_file(&path);
+            }
+            let hashed = hash_one_input(&path, args);
+            if let Some(progress) = &progress {
+                progress.finish_file(meta.size);
+            }
+            errors.record(&hashed.status);
+            let hashes = match &hashed.status {
+                Status::Hashed(hashes) => hashes,
+                status => {
+                    let status_name = if matches!(status, Status::Unstable) {
+                        "unstable"
+                    } else {
+                        "error"
+                    };
+                    let error = match status {
+                        Status::Error(kind) => Some(kind.as_str()),
+                        _ => None,
+                    };
+                    differs |= report_line(report, &key, status_name, None, None, error, "")?;
+                    continue;
+                }
+            };
+            let found: Vec<&str> = hashes.split(',').collect();
+            let expected: Vec<String> = digests
+                .iter()
+                .map(|(_, d)| d.to_ascii_lowercase())
+                .collect();
+            let found: Vec<&str> = digests.iter().map(|(i, _)| found[*i]).collect();
+            if expected != found {
+                let (expected, found) = (expected.join(","), found.join(","));
+                differs |= report_line(
+                    report,
+                    &key,
+                    "mismatch",
+                    Some(&expected),
+                    Some(&found),
+                    None,
+                    "",
+                )?;
+                continue;
+            }
+        }
+        if diffs.is_empty() {
+            report_line(report, &key, "ok", None, None, None, "")?;
+            continue;
+        }
+        let join = |f: fn(&(&str, String, String)) -> String| {
+            diffs.iter().map(f).collect::<Vec<_>>().join(" ")
+        };
+        let expected = join(|(keyword, expected, _)| format!("{}={}", keyword, expected));
+        let found = join(|(keyword, _, found)| format!("{}={}", keyword, found));
+        let detail = diffs
+            .iter()
+            .map(|(keyword, expected, found)| {
+                format!("{} expected {}, found {}", keyword, expected, found)
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        differs |= report_line(
+            report,
+            &key,
+            "changed",
+            Some(&expected),
+            Some(&found),
+            None,
+            &detail,
+        )?;
+    }
+
+    // Then whatever's on disk that the spec doesn't describe.
+    for walked in walk::walk_tree(args, root) {
+        if interrupted() {
+            break;
+        }
+        let path = match walked? {
+            Walked::File(path) | Walked::Other(path) => path,
+            Walked::Error(e) => {
+                let e = anyhow::Error::from(e);
+                eprintln!("{}: {}", NAME, e);
+                errors.record(&Status::Error(error_kind(&e)));
+                continue;
+            }
+        };
+        let rel = path.strip_prefix(root).unwrap_or(&path);
+        // The root doesn't have to be in the spec.
+        if rel.as_os_str().is_empty()
+            || described.contains(rel)
+            || ignored.iter().any(|dir| rel.starts_with(dir))
+        {
+            continue;
+        }
+        differs |= report_line(report, &report_key(rel), "new", None, None, None, "")?;
+    }
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+    Ok((differs, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `bsdtar --format=mtree --options='!all,type,size,mode,sha256'` of a
+    // tree with a space and a backslash in its names.
+    const FULL_PATH: &str = r#"#mtree
+. mode=755 type=dir
+./a\040b mode=644 type=file size=3 sha256digest=7692c3ad3540bb803c020b3aee66cd8887123234ea0c6e7143c0add73ff431ed
+./back\134slash mode=644 type=file size=3 sha256digest=3fc4ccfe745870e2c0d99f71f30ff0656c8dedd41cc1d7d3d376b0dbe685e2f3
+./z mode=644 type=file size=4 sha256digest=222b0bd51fcef7e65c2e62db2ed65457013bab56be6fafeb19ee11d453153c80
+./sub\040dir mode=755 type=dir
+./sub\040dir/g mode=644 type=file size=4 sha256digest=04efaf080f5a3e74e1c29d1ca6a48569382cbbcd324e8d59d2b83ef21c039f00
+./sub\040dir/deep mode=755 type=dir
+./sub\040dir/deep/f mode=644 type=file size=5 sha256digest=8b5b9db0c13db24256c829aa364aa90c6d2eba318b9232a4ab9313b954d3555f
+"#;
+
+    // The same tree with `--format=mtree-classic --options=...,use-set`.
+    const CLASSIC: &str = r#"#mtree
+
+# .
+/set type=file mode=644
+.               mode=755 type=dir
+    a\040b      size=3 \
+                sha256digest=7692c3ad3540bb803c020b3aee66cd8887123234ea0c6e7143c0add73ff431ed
+    back\134slash \
+                size=3 \
+                sha256digest=3fc4ccfe745870e2c0d99f71f30ff0656c8dedd41cc1d7d3d376b0dbe685e2f3
+    z           size=4 \
+                sha256digest=222b0bd51fcef7e65c2e62db2ed65457013bab56be6fafeb19ee11d453153c80
+
+# ./sub dir
+sub\040dir      mode=755 type=dir
+    g           size=4 \
+                sha256digest=04efaf080f5a3e74e1c29d1ca6a48569382cbbcd324e8d59d2b83ef21c039f00
+
+# ./sub dir/deep
+deep            mode=755 type=dir
+    f           size=5 \
+                sha256digest=8b5b9db0c13db24256c829aa364aa90c6d2eba318b9232a4ab9313b954d3555f
+# ./sub dir/deep
+..
+
+# ./sub dir
+..
+
+..
+
+"#;
+
+    fn summary(text: &str) -> Vec<(PathBuf, String, Option<String>)> {
+        let mut entries: Vec<_> = parse_spec(text)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let kind = entry.get("type").unwrap().to_string();
+                let size = entry.get("size").map(str::to_string);
+                (entry.rel, kind, size)
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn parses_full_path_specs() {
+        let expected = [
+            ("", "dir", None),
+            ("a b", "file", Some("3")),
+            ("back\\slash", "file", Some("3")),
+            ("sub dir", "dir", None),
+            ("sub dir/deep", "dir", None),
+            ("sub dir/deep/f", "file", Some("5")),
+            ("sub dir/g", "file", Some("4")),
+            ("z", "file", Some("4")),
+        ]
+        .map(|(rel, kind, size)| {
+            (
+                PathBuf::from(rel),
+                kind.to_string(),
+                size.map(str::to_string),
+            )
+        });
+        assert_eq!(summary(FULL_PATH), expected);
+
+        let entries = parse_spec(FULL_PATH).unwrap();
+        let f = entries
+            .iter()
+            .find(|e| e.rel == Path::new("sub dir/deep/f"))
+            .unwrap();
+        assert_eq!(f.get("mode"), Some("644"));
+        assert_eq!(
+            f.get("sha256digest"),
+            Some("8b5b9db0c13db24256c829aa364aa90c6d2eba318b9232a4ab9313b954d3555f")
+        );
+    }
+
+    #[test]
+    fn parses_classic_specs_like_full_path_ones() {
+        assert_eq!(summary(CLASSIC), summary(FULL_PATH));
+
+        // /set supplies the mode, and `..` leaves sub dir/deep, so z and g
+        // land where they belong.
+        let entries = parse_spec(CLASSIC).unwrap();
+        let g = entries
+            .iter()
+            .find(|e| e.rel == Path::new("sub dir/g"))
+            .unwrap();
+        assert_eq!(g.get("mode"), Some("644"));
+        assert_eq!(
+            g.get("sha256digest"),
+            Some("04efaf080f5a3e74e1c29d1ca6a48569382cbbcd324e8d59d2b83ef21c039f00")
+        );
+        let root = entries.iter().find(|e| e.rel == Path::new("")).unwrap();
+        assert_eq!(root.get("mode"), Some("755"));
+    }
+
+    #[test]
+    fn continues_only_on_an_unescaped_backslash() {
+        let spec = "/set type=file\ntrail\\\\\nsplit \\\n    size=3\nlast\\\\\\\n    size=4\n";
+        let entries = parse_spec(spec).unwrap();
+        let summary: Vec<(PathBuf, Option<&str>)> = entries
+            .iter()
+            .map(|e| (e.rel.clone(), e.keywords.get("size").map(String::as_str)))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (PathBuf::from("trail\\"), None),
+                (PathBuf::from("split"), Some("3")),
+                (PathBuf::from("last\\"), Some("4")),
+            ]
+        );
+    }
+
+    #[test]
+    fn pops_out_of_directories() {
+        let spec = "/set type=file\na type=dir\nb type=dir\nx\\040y\n..\nback\\\\slash\n..\nz\n";
+        let rels: Vec<PathBuf> = parse_spec(spec)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.rel)
+            .collect();
+        assert_eq!(
+            rels,
+            ["a", "a/b", "a/b/x y", "a/back\\slash", "z"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn rejects_names_outside_the_tree() {
+        assert!(parse_spec("./../etc/passwd type=file\n").is_err());
+        assert!(parse_spec("/etc/passwd type=file\n").is_err());
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(decode("a\\040b"), b"a b");
+        assert_eq!(decode("back\\134slash"), b"back\\slash");
+        assert_eq!(decode("back\\\\slash"), b"back\\slash");
+        assert_eq!(decode("tab\\tand\\snl\\n"), b"tab\tand nl\n");
+        assert_eq!(decode("\\377"), [0xff]);
+        // Not an escape: left alone.
+        assert_eq!(decode("trailing\\"), b"trailing\\");
+        assert_eq!(decode("\\9x"), b"9x");
+    }
+
+    #[test]
+    fn encodes_what_decode_reads() {
+        let name = b"a b\\c#d*\n\xff";
+        assert_eq!(encode(name), "a\\040b\\134c\\043d\\052\\012\\377");
+        assert_eq!(decode(&encode(name)), name);
+    }
+}
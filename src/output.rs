@@ -0,0 +1,161 @@
+//! Writing the manifest, either to stdout or atomically to a file.
+
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+// The temporary file a manifest is written to, next to its destination so
+// that the final rename stays on one filesystem. The name is fixed, so that a
+// resumed run overwrites the one an interrupted run left behind, rather than
+// hashing it.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    name.into()
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    name.into()
+}
+
+// Where the manifest goes. Either way, entries are written a line at a time,
+// so that a crash midway still leaves every finished entry on disk. Files are
+// written to a temporary file and renamed over the destination by finish(),
+// so that a killed run never truncates the last good manifest.
+pub enum Output {
+    Stdout(io::Stdout),
+    File {
+        file: LineWriter<File>,
+        temp_path: PathBuf,
+        path: PathBuf,
+    },
+}
+
+impl Output {
+    // None or - means stdout.
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) if path != Path::new("-") => path,
+            _ => return Ok(Self::Stdout(io::stdout())),
+        };
+        let temp_path = temp_path(path);
+        let file = File::create(&temp_path)
+            .with_context(|| format!("Failed to create {}", temp_path.display()))?;
+        Ok(Self::File {
+            file: LineWriter::new(file),
+            temp_path,
+            path: path.to_path_buf(),
+        })
+    }
+
+    // Syncs the temporary file and renames it into place. With `backup`, the
+    // previous manifest (if any) is kept alongside as `<path>.bak`.
+    pub fn finish(self, backup: bool) -> Result<()> {
+        let (temp_path, path) = match self.sync()? {
+            Some(paths) => paths,
+            None => return Ok(()),
+        };
+        if backup && path.exists() {
+            let backup_path = backup_path(&path);
+            // A hard link keeps the old manifest in place until the rename
+            // below replaces it. Not every filesystem has them, though.
+            let _ = fs::remove_file(&backup_path);
+            if fs::hard_link(&path, &backup_path).is_err() {
+                fs::copy(&path, &backup_path).with_context(|| {
+                    format!(
+                        "Failed to back up {} to {}",
+                        path.display(),
+                        backup_path.display()
+                    )
+                })?;
+            }
+        }
+        rename(&temp_path, &path)?;
+        // Whatever an earlier interrupted run left is out of date now.
+        let _ = fs::remove_file(partial_path(&path));
+        sync_parent_dir(&path)
+    }
+
+    // Like finish(), for a run that was interrupted. The entries so far are
+    // renamed to `<path>.partial`, leaving the last complete manifest alone.
+    // Returns where they went, or None for stdout.
+    pub fn finish_partial(self) -> Result<Option<PathBuf>> {
+        let (temp_path, path) = match self.sync()? {
+            Some(paths) => paths,
+            None => return Ok(None),
+        };
+        let partial_path = partial_path(&path);
+        rename(&temp_path, &partial_path)?;
+        sync_parent_dir(&path)?;
+        Ok(Some(partial_path))
+    }
+
+    // Flushes the output, and for files syncs the temporary file and returns
+    // it along with the destination.
+    fn sync(self) -> Result<Option<(PathBuf, PathBuf)>> {
+        match self {
+            Self::Stdout(mut stdout) => {
+                stdout.flush()?;
+                Ok(None)
+            }
+            Self::File {
+                file,
+                temp_path,
+                path,
+            } => {
+                file.into_inner()
+                    .map_err(io::IntoInnerError::into_error)?
+                    .sync_all()?;
+                Ok(Some((temp_path, path)))
+            }
+        }
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)
+        .with_context(|| format!("Failed to rename {} to {}", from.display(), to.display()))
+}
+
+// The rename isn't durable until the directory entry is.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(stdout) => stdout.write(buf),
+            Self::File { file, .. } => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File { file, .. } => file.flush(),
+        }
+    }
+}
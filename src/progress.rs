@@ -0,0 +1,201 @@
+//! `--progress`: how far a run has got, on stderr. A terminal gets a status
+//! line redrawn in place; anything else gets a plain log line now and then.
+
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::walk::{self, Walked};
+use crate::{Args, NAME};
+
+const TTY_INTERVAL: Duration = Duration::from_millis(250);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct State {
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    // Files a resumed run took from its checkpoint count as done, but not
+    // towards the throughput.
+    bytes_skipped: AtomicU64,
+    // Filled in by the pre-scan, which runs alongside the hashing.
+    files_total: AtomicU64,
+    bytes_total: AtomicU64,
+    scanned: AtomicBool,
+    current: Mutex<PathBuf>,
+    stop: AtomicBool,
+}
+
+pub struct Progress {
+    state: Arc<State>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+// What the pre-scan counts: the inputs, from wherever a resumed run skips
+// ahead to, or the one tree an mtree spec describes.
+pub enum Scan {
+    Inputs(Option<PathBuf>),
+    Tree(PathBuf),
+}
+
+impl Progress {
+    // Totals come from a pre-scan on its own thread, which skips the same
+    // files the walk does and only stats files, so it stays well ahead of the
+    // hashing. Without anything to scan (for --files-from), the totals stay
+    // unknown and there's no ETA.
+    pub fn start(args: &'static Args, scan: Option<Scan>) -> Self {
+        let state = Arc::new(State::default());
+        let mut threads = Vec::new();
+        if let Some(scan) = scan {
+            let state = state.clone();
+            threads.push(thread::spawn(move || prescan(args, scan, &state)));
+        }
+        let tty = io::stderr().is_terminal();
+        let reporter = state.clone();
+        threads.push(thread::spawn(move || report(&reporter, tty)));
+        Self { state, threads }
+    }
+
+    pub fn start_file(&self, path: &Path) {
+        *self.state.current.lock().unwrap() = path.to_path_buf();
+    }
+
+    pub fn finish_file(&self, len: u64) {
+        self.state.files_done.fetch_add(1, Ordering::Relaxed);
+        self.state.bytes_done.fetch_add(len, Ordering::Relaxed);
+    }
+
+    // A file that didn't need hashing.
+    pub fn skip_file(&self, len: u64) {
+        self.finish_file(len);
+        self.state.bytes_skipped.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn finish(self) {
+        self.state.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn prescan(args: &Args, scan: Scan, state: &State) {
+    let walked: Box<dyn Iterator<Item = _>> = match &scan {
+        Scan::Inputs(after) => match walk::scan_inputs(args, after.as_deref()) {
+            Ok(walked) => Box::new(walked),
+            Err(_) => return,
+        },
+        Scan::Tree(root) => Box::new(walk::scan_tree(args, root)),
+    };
+    for walked in walked {
+        if state.stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let path = match walked {
+            Ok(Walked::File(path)) if path != Path::new("-") => path,
+            _ => continue,
+        };
+        let len = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        state.files_total.fetch_add(1, Ordering::Relaxed);
+        state.bytes_total.fetch_add(len, Ordering::Relaxed);
+    }
+    state.scanned.store(true, Ordering::Relaxed);
+}
+
+// Sleeps in short steps, so that finish() doesn't wait out a whole
+// LOG_INTERVAL.
+fn report(state: &State, tty: bool) {
+    let started = Instant::now();
+    let interval = if tty { TTY_INTERVAL } else { LOG_INTERVAL };
+    let mut last = Instant::now();
+    while !state.stop.load(Ordering::Relaxed) {
+        thread::sleep(TTY_INTERVAL);
+        if last.elapsed() < interval {
+            continue;
+        }
+        last = Instant::now();
+        let line = status_line(state, started.elapsed());
+        let mut stderr = io::stderr().lock();
+        let _ = if tty {
+            let current = state.current.lock().unwrap();
+            write!(stderr, "\r\x1b[K{}, {}", line, shorten(&current, 40))
+        } else {
+            writeln!(stderr, "{}: {}", NAME, line)
+        };
+    }
+    // A log gets a final line, so that even short runs report their totals.
+    if tty {
+        let _ = write!(io::stderr(), "\r\x1b[K");
+    } else {
+        eprintln!("{}: {}", NAME, status_line(state, started.elapsed()));
+    }
+}
+
+// e.g. `120/5000 files, 1.2 GiB/50.0 GiB, 85.3 MiB/s, ETA 9m41s`. Until the
+// pre-scan finishes, totals are marked with a `+` and there's no ETA.
+fn status_line(state: &State, elapsed: Duration) -> String {
+    let files_done = state.files_done.load(Ordering::Relaxed);
+    let bytes_done = state.bytes_done.load(Ordering::Relaxed);
+    let bytes_hashed = bytes_done - state.bytes_skipped.load(Ordering::Relaxed);
+    let files_total = state.files_total.load(Ordering::Relaxed);
+    let bytes_total = state.bytes_total.load(Ordering::Relaxed);
+    let scanned = state.scanned.load(Ordering::Relaxed);
+    let rate = bytes_hashed as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut line = format!("{} files, {}", files_done, format_bytes(bytes_done as f64));
+    if files_total > 0 || scanned {
+        let more = if scanned { "" } else { "+" };
+        line = format!(
+            "{}/{}{} files, {}/{}{}",
+            files_done,
+            files_total,
+            more,
+            format_bytes(bytes_done as f64),
+            format_bytes(bytes_total as f64),
+            more
+        );
+    }
+    line += &format!(", {}/s", format_bytes(rate));
+    if scanned && rate > 0.0 {
+        let eta = bytes_total.saturating_sub(bytes_done) as f64 / rate;
+        line += &format!(", ETA {}", format_duration(eta as u64));
+    }
+    line
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", value as u64)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+// Keeps the end of the path, which is the part that changes.
+fn shorten(path: &Path, max: usize) -> String {
+    let path = path.to_string_lossy();
+    let count = path.chars().count();
+    if count <= max {
+        return path.into_owned();
+    }
+    let tail: String = path.chars().skip(count - (max - 3)).collect();
+    format!("...{}", tail)
+}
@@ -0,0 +1,184 @@
+//! Constant-memory comparison of manifests written by `hash --sort`, for
+//! trees too big to hold in a HashMap.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::format::{self, Format, Report};
+use crate::{
+    hash_inputs, interrupted, parse_manifest_line, report_diff, unescape, Args, ErrorSummary,
+    PARTIAL_MARKER,
+};
+use crate::{hashdeep, mtree};
+
+// The order `hash --sort` writes entries in: by path, component by component,
+// which is the order a sorted walk visits a tree in. Keys are compared
+// unescaped, so that an escaped newline doesn't sort like a backslash.
+pub fn manifest_order(a: &str, b: &str) -> Ordering {
+    if !a.contains('\\') && !b.contains('\\') {
+        return Path::new(a).cmp(Path::new(b));
+    }
+    let a = unescape(a).unwrap_or_else(|_| a.to_string());
+    let b = unescape(b).unwrap_or_else(|_| b.to_string());
+    Path::new(&a).cmp(Path::new(&b))
+}
+
+fn unsortable(path: &Path, what: &str) -> anyhow::Error {
+    anyhow!(
+        "{} is {}. Only text and JSON Lines manifests can be compared in sorted order.",
+        path.display(),
+        what
+    )
+}
+
+// A manifest read one entry at a time. Fails if the entries aren't strictly
+// in manifest_order(), since the merge-joins below would silently report
+// nonsense otherwise.
+struct SortedManifest {
+    path: PathBuf,
+    lines: io::Lines<BufReader<File>>,
+    current: Option<(String, String)>,
+}
+
+impl SortedManifest {
+    fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut manifest = Self {
+            path: path.to_path_buf(),
+            lines: BufReader::new(file).lines(),
+            current: None,
+        };
+        manifest.advance()?;
+        Ok(manifest)
+    }
+
+    fn current(&self) -> Option<(&str, &str)> {
+        self.current
+            .as_ref()
+            .map(|(path, value)| (path.as_str(), value.as_str()))
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        let line = match self.lines.next() {
+            Some(line) => line?,
+            None => {
+                self.current = None;
+                return Ok(());
+            }
+        };
+        if line == PARTIAL_MARKER {
+            return self.advance();
+        }
+        // Only the first line can say it's an mtree spec; later ones are
+        // paths, which may well start with `#`.
+        let format = if line == hashdeep::HEADER {
+            Format::Hashdeep
+        } else if self.current.is_none() && mtree::is_spec(&line) {
+            Format::Mtree
+        } else {
+            format::detect(&line)
+        };
+        let (path, value) = match format {
+            Format::Text => {
+                let (path, value) = parse_manifest_line(&line)?;
+                (path.to_string(), value.to_string())
+            }
+            Format::Jsonl => format::parse_entry(&serde_json::from_str(&line)?)?,
+            Format::Json => return Err(unsortable(&self.path, "a JSON document")),
+            Format::Junit => return Err(unsortable(&self.path, "a JUnit report")),
+            Format::Gnu => return Err(unsortable(&self.path, "a GNU-style checkfile")),
+            Format::Bsd => return Err(unsortable(&self.path, "a BSD-style checkfile")),
+            Format::Hashdeep => return Err(unsortable(&self.path, "a hashdeep file")),
+            Format::Mtree => return Err(unsortable(&self.path, "an mtree spec")),
+        };
+        if let Some((last, _)) = &self.current {
+            if manifest_order(last, &path) != Ordering::Less {
+                bail!(
+                    "{} is not sorted at {}. Write it with `hash --sort`.",
+                    self.path.display(),
+                    path,
+                );
+            }
+        }
+        self.current = Some((path, value));
+        Ok(())
+    }
+}
+
+// Compares two sorted manifests a line at a time. Returns true if there were
+// any differences.
+pub fn diff_sorted(old: &Path, new: &Path, report: &mut Report) -> Result<bool> {
+    let mut old = SortedManifest::open(old)?;
+    let mut new = SortedManifest::open(new)?;
+    let mut differs = false;
+    loop {
+        let order = match (old.current(), new.current()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((old_path, _)), Some((new_path, _))) => manifest_order(old_path, new_path),
+        };
+        match order {
+            Ordering::Less => {
+                let (path, value) = old.current().unwrap();
+                differs |= report_diff(report, path, Some(value), None)?;
+                old.advance()?;
+            }
+            Ordering::Greater => {
+                let (path, value) = new.current().unwrap();
+                differs |= report_diff(report, path, None, Some(value))?;
+                new.advance()?;
+            }
+            Ordering::Equal => {
+                let (path, old_value) = old.current().unwrap();
+                differs |=
+                    report_diff(report, path, Some(old_value), new.current().map(|(_, v)| v))?;
+                old.advance()?;
+                new.advance()?;
+            }
+        }
+    }
+    Ok(differs)
+}
+
+// Hashes the inputs in sorted order and merge-joins them against a sorted
+// manifest as each file is hashed. Returns whether anything differed, and the
+// summary of files that couldn't be hashed.
+pub fn verify_sorted(args: &'static Args, report: &mut Report) -> Result<(bool, ErrorSummary)> {
+    let mut expected = SortedManifest::open(args.manifest_path())?;
+    let mut differs = false;
+    let errors = hash_inputs(args, None, |_, path, entry| {
+        let found = entry.status.to_string();
+        // Everything in the manifest that sorts before this file is missing
+        // from the tree.
+        while let Some((expected_path, expected_value)) = expected.current() {
+            match manifest_order(expected_path, &path) {
+                Ordering::Less => {
+                    differs |= report_diff(report, expected_path, Some(expected_value), None)?;
+                    expected.advance()?;
+                }
+                Ordering::Equal => {
+                    differs |= report_diff(report, &path, Some(expected_value), Some(&found))?;
+                    expected.advance()?;
+                    return Ok(());
+                }
+                Ordering::Greater => break,
+            }
+        }
+        differs |= report_diff(report, &path, None, Some(&found))?;
+        Ok(())
+    })?;
+    // Everything we didn't get to would show up as MISSING.
+    if interrupted() {
+        return Ok((true, errors));
+    }
+    while let Some((path, value)) = expected.current() {
+        differs |= report_diff(report, path, Some(value), None)?;
+        expected.advance()?;
+    }
+    Ok((differs, errors))
+}
@@ -0,0 +1,374 @@
+//! Turning the -i inputs and --files-from list into the stream of files to
+//! hash.
+
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::iter::Peekable;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use walkdir::WalkDir;
+
+use crate::checkpoint::checkpoint_path;
+use crate::output::{backup_path, partial_path, temp_path};
+use crate::{Args, Mode, NAME};
+
+// One item in the stream of inputs.
+pub enum Walked {
+    // A file to hash.
+    File(PathBuf),
+    // A directory, symlink or special file. Only walk_tree() yields these.
+    Other(PathBuf),
+    // A directory entry we couldn't read, e.g. an unreadable directory. These
+    // are recorded like files we couldn't read, when we know the path.
+    Error(walkdir::Error),
+}
+
+impl Walked {
+    fn path(&self) -> Option<&Path> {
+        match self {
+            Self::File(path) | Self::Other(path) => Some(path),
+            Self::Error(e) => e.path(),
+        }
+    }
+}
+
+type Stream = Box<dyn Iterator<Item = Result<Walked>>>;
+
+// Identifies a file regardless of the path we reached it by.
+#[cfg(unix)]
+#[derive(PartialEq, Eq)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+#[cfg(unix)]
+impl FileId {
+    fn of(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = fs::metadata(path).ok()?;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+#[derive(PartialEq, Eq)]
+struct FileId(PathBuf);
+
+#[cfg(not(unix))]
+impl FileId {
+    fn of(path: &Path) -> Option<Self> {
+        fs::canonicalize(path).ok().map(Self)
+    }
+}
+
+// Files that must never be hashed into a manifest, even when they're inside
+// the walked tree: the manifest being written (along with its temp file and
+// backup) and the manifest being verified against. Hashing those would make
+// the manifest depend on itself, and verification could never pass.
+struct Excluded {
+    ids: Vec<FileId>,
+    // The --progress pre-scan skips the same files, but the walk itself
+    // already says so.
+    quiet: bool,
+}
+
+impl Excluded {
+    fn new(args: &Args, quiet: bool) -> Self {
+        let paths = match args.mode {
+            Mode::Hash => match args.output_path() {
+                Some(path) if path != Path::new("-") => {
+                    vec![
+                        path.to_path_buf(),
+                        temp_path(path),
+                        backup_path(path),
+                        partial_path(path),
+                        checkpoint_path(path),
+                    ]
+                }
+                _ => Vec::new(),
+            },
+            Mode::Verify => {
+                let path = args.manifest_path();
+                vec![path.to_path_buf(), backup_path(path)]
+            }
+            Mode::Check
+            | Mode::Diff
+            | Mode::BagCreate
+            | Mode::BagValidate
+            | Mode::GoMod
+            | Mode::CargoChecksum => Vec::new(),
+        };
+        Self {
+            ids: paths.iter().filter_map(|path| FileId::of(path)).collect(),
+            quiet,
+        }
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        if self.ids.is_empty() {
+            return false;
+        }
+        let excluded = FileId::of(path).is_some_and(|id| self.ids.contains(&id));
+        if excluded && !self.quiet {
+            eprintln!("{}: skipping {}, it's a manifest", NAME, path.display());
+        }
+        excluded
+    }
+
+    // Directory entries already know their inode, so on Unix most of them can
+    // be ruled out without another stat.
+    fn contains_entry(&self, entry: &walkdir::DirEntry) -> bool {
+        #[cfg(unix)]
+        {
+            use walkdir::DirEntryExt;
+            if !self.ids.iter().any(|id| id.ino == entry.ino()) {
+                return false;
+            }
+        }
+        self.contains(entry.path())
+    }
+}
+
+// Drops inputs that are the same as, or inside, another input, so that
+// overlapping roots don't record the same file twice. Inputs that can't be
+// canonicalized (stdin, or paths that don't exist) are always kept, and fail
+// later when we try to hash them.
+fn distinct_roots(file_args: &[PathBuf], quiet: bool) -> Vec<&Path> {
+    let canonical: Vec<Option<PathBuf>> = file_args
+        .iter()
+        .map(|path| {
+            if path == Path::new("-") {
+                None
+            } else {
+                fs::canonicalize(path).ok()
+            }
+        })
+        .collect();
+    let mut roots = Vec::new();
+    for (i, path) in file_args.iter().enumerate() {
+        // When two inputs are the same, the first one wins.
+        let covered_by = canonical[i].as_ref().and_then(|this| {
+            (0..file_args.len()).find(|&j| match &canonical[j] {
+                Some(other) => j != i && this.starts_with(other) && (this != other || j < i),
+                None => false,
+            })
+        });
+        match covered_by {
+            Some(_) if quiet => {}
+            Some(j) => eprintln!(
+                "{}: {} is already included in {}, skipping",
+                NAME,
+                path.display(),
+                file_args[j].display(),
+            ),
+            None => roots.push(path.as_path()),
+        }
+    }
+    roots
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(|s| OsString::from(s).into())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Reads the paths listed in --files-from lazily, so that a list of millions of
+// files never has to be held in memory. Empty entries are skipped.
+fn read_files_from(path: &Path, args: &Args) -> Result<impl Iterator<Item = io::Result<PathBuf>>> {
+    let reader: Box<dyn BufRead> = if path == Path::new("-") {
+        // In keyed mode, stdin has already been used for the key.
+        if args.keyed() {
+            bail!("Cannot read --files-from - in keyed mode");
+        }
+        Box::new(io::stdin().lock())
+    } else {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Box::new(BufReader::new(file))
+    };
+    let delimiter = if args.null() { b'\0' } else { b'\n' };
+    Ok(reader
+        .split(delimiter)
+        .filter(|entry| !matches!(entry, Ok(bytes) if bytes.is_empty()))
+        .map(|entry| entry.and_then(bytes_to_path)))
+}
+
+// With `after`, a sorted walk skips the directories that lie entirely before
+// that path without reading them. walk_inputs() drops whatever else is left
+// up to it.
+fn walk_root(
+    root: &Path,
+    sort: bool,
+    excluded: Rc<Excluded>,
+    everything: bool,
+    after: Option<&Path>,
+) -> Stream {
+    if !root.is_dir() {
+        if excluded.contains(root) || after.is_some_and(|after| root <= after) {
+            return Box::new(std::iter::empty());
+        }
+        return Box::new(std::iter::once(Ok(Walked::File(root.to_path_buf()))));
+    }
+    let mut walk = WalkDir::new(root);
+    if sort {
+        walk = walk.sort_by_file_name();
+    }
+    let after = after.map(Path::to_path_buf);
+    let walk = walk.into_iter().filter_entry(move |entry| match &after {
+        Some(after) => entry.path() > after.as_path() || after.starts_with(entry.path()),
+        None => true,
+    });
+    Box::new(walk.filter_map(move |entry| match entry {
+        Ok(entry) if entry.file_type().is_file() && !excluded.contains_entry(&entry) => {
+            Some(Ok(Walked::File(entry.into_path())))
+        }
+        Ok(entry) if everything && !entry.file_type().is_file() => {
+            Some(Ok(Walked::Other(entry.into_path())))
+        }
+        Ok(_) => None,
+        Err(e) => Some(Ok(Walked::Error(e))),
+    }))
+}
+
+// Directories in the list are skipped, since `find` output includes them and
+// walking them would record their contents twice.
+fn walk_files_from(path: &Path, args: &Args, excluded: Rc<Excluded>) -> Result<Stream> {
+    let files = read_files_from(path, args)?.filter_map(move |path| match path {
+        Ok(path) if path.is_dir() || excluded.contains(&path) => None,
+        Ok(path) => Some(Ok(Walked::File(path))),
+        Err(e) => Some(Err(
+            anyhow::Error::from(e).context("Failed to read --files-from")
+        )),
+    });
+    if !args.sort() {
+        return Ok(Box::new(files));
+    }
+    let mut files = files.collect::<Result<Vec<Walked>>>()?;
+    files.sort_by(|a, b| a.path().cmp(&b.path()));
+    Ok(Box::new(files.into_iter().map(Ok)))
+}
+
+// Merges streams that are each sorted by path into a single sorted stream.
+// Path ordering compares component by component, which is exactly the order
+// that a WalkDir sorted by file name visits a tree in. Items without a path
+// (fatal errors, or walk errors that didn't say where) come out first.
+struct MergeByPath {
+    streams: Vec<Peekable<Stream>>,
+}
+
+impl Iterator for MergeByPath {
+    type Item = Result<Walked>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<(usize, Option<&Path>)> = None;
+        for (i, stream) in self.streams.iter_mut().enumerate() {
+            let path = match stream.peek() {
+                Some(Ok(walked)) => walked.path(),
+                Some(Err(_)) => None,
+                None => continue,
+            };
+            if next.is_none_or(|(_, min)| path < min) {
+                next = Some((i, path));
+            }
+        }
+        let (i, _) = next?;
+        self.streams[i].next()
+    }
+}
+
+// Every file to hash: the files under each -i input, then the files listed in
+// --files-from. With --sort, these are merged into a single stream in path
+// order instead, and `after` skips ahead past a path a resumed run already got
+// through.
+pub fn walk_inputs(
+    args: &Args,
+    after: Option<&Path>,
+) -> Result<impl Iterator<Item = Result<Walked>>> {
+    inputs(args, after, args.sort(), false)
+}
+
+// The same files as walk_inputs(), in any order and without the warnings, for
+// the --progress pre-scan. Not for --files-from, which may be a pipe that only
+// the walk can read.
+pub fn scan_inputs(
+    args: &Args,
+    after: Option<&Path>,
+) -> Result<impl Iterator<Item = Result<Walked>>> {
+    inputs(args, after, false, true)
+}
+
+fn inputs(
+    args: &Args,
+    after: Option<&Path>,
+    sort: bool,
+    quiet: bool,
+) -> Result<impl Iterator<Item = Result<Walked>>> {
+    let mut roots = distinct_roots(&args.file_args, quiet);
+    if sort {
+        roots.sort();
+    }
+    let excluded = Rc::new(Excluded::new(args, quiet));
+    let mut streams: Vec<Stream> = roots
+        .into_iter()
+        .map(|root| walk_root(root, sort, excluded.clone(), false, after))
+        .collect();
+    if let Some(files_from) = args.files_from() {
+        streams.push(walk_files_from(files_from, args, excluded)?);
+    }
+    let walked: Stream = if sort {
+        Box::new(MergeByPath {
+            streams: streams.into_iter().map(Iterator::peekable).collect(),
+        })
+    } else {
+        Box::new(streams.into_iter().flatten())
+    };
+    let after = after.map(Path::to_path_buf);
+    Ok(walked.filter(move |walked| match (walked, &after) {
+        (Ok(walked), Some(after)) => walked.path().is_none_or(|path| path > after.as_path()),
+        _ => true,
+    }))
+}
+
+// Everything under a single directory, in sorted order: the directory itself,
+// subdirectories, symlinks (which aren't followed) and special files as well
+// as regular files. mtree specs describe all of them.
+pub fn walk_tree(args: &Args, root: &Path) -> impl Iterator<Item = Result<Walked>> {
+    walk_root(root, true, Rc::new(Excluded::new(args, false)), true, None)
+}
+
+// walk_tree() without the warnings, for the --progress pre-scan.
+pub fn scan_tree(args: &Args, root: &Path) -> impl Iterator<Item = Result<Walked>> {
+    walk_root(root, false, Rc::new(Excluded::new(args, true)), true, None)
+}
+
+// `path` relative to `dir`, with `/` between components whatever the
+// platform, as bags, .cargo-checksum.json and Go's dirhash all list files.
+pub fn relative_slash_path(dir: &Path, path: &Path) -> Result<String> {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    let mut parts = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .with_context(|| format!("{} isn't valid UTF-8", path.display()))?,
+            ),
+            Component::CurDir => {}
+            _ => bail!("{} isn't inside {}", path.display(), dir.display()),
+        }
+    }
+    Ok(parts.join("/"))
+}